@@ -14,14 +14,801 @@ import sys
 import numpy as np
 import numpy.testing as npt
 import os
-from qoqo_calculator_pyo3 import Calculator
+from qoqo_calculator_pyo3 import Calculator, CalculatorFloat, CalculatorComplex
+from qoqo_calculator_pyo3 import SharedCalculator, parse_string, parse_string_partial
+from qoqo_calculator_pyo3 import validate_string
+import threading
 import math
+import operator
+import copy
+import pickle
 
 def test_calculator_parse():
     c = Calculator() 
     c.set("a",1)
     assert c.parse_get("sin(a+1)") == math.sin(2)
 
+def test_calculator_builtins():
+    c = Calculator(x=0.5)
+    assert c.parse_str("arsinh(x)") == math.asinh(0.5)
+    assert c.parse_str("arcosh(x + 1)") == math.acosh(1.5)
+    assert c.parse_str("artanh(x)") == math.atanh(0.5)
+    assert math.isclose(c.parse_str("erf(x)"), math.erf(0.5))
+    assert math.isclose(c.parse_str("tgamma(x)"), math.gamma(0.5))
+    assert math.isclose(c.parse_str("lgamma(10)"), math.lgamma(10))
+    assert c.parse_str("delta(0)") == 1.0
+    assert c.parse_str("delta(x)") == 0.0
+    assert c.parse_str("theta(0)") == 0.5
+    assert c.parse_str("theta(-x)") == 0.0
+    assert c.parse_str("theta(x)") == 1.0
+    assert c.parse_str("-2^2") == 4.0
+    assert c.parse_str("-x**2 + 2^-1") == 0.75
+    assert c.parse_str("sign(0)") == 1.0
+    with pytest.raises(ValueError):
+        c.parse_str("2 3")
+    assert c.parse_str("parity(2)") == 1.0
+    assert c.parse_str("parity(3.2)") == -1.0
+    assert c.parse_str("parity(-1)") == -1.0
+    assert math.isclose(c.parse_str("factorial(4)"), 24.0)
+    assert math.isclose(c.parse_str("3! + 2^3!"), 6.0 + 2.0**6)
+    assert c.parse_str("x != 0.5") == 0.0
+    assert c.parse_str("a = 2 # comment\n# another comment\na + x") == 2.5
+
+def test_calculator_nesting():
+    c = Calculator(x=1.0)
+    nested = "(" * 100 + "x" + ")" * 100
+    assert c.parse_str(nested) == 1.0
+    assert c.partial(nested) == 1.0
+    too_deep = "(" * 1000 + "x" + ")" * 1000
+    with pytest.raises(ValueError, match="nested too deeply"):
+        c.parse_str(too_deep)
+    with pytest.raises(ValueError, match="nested too deeply"):
+        c.validate(too_deep)
+    with pytest.raises(ValueError, match="nested too deeply"):
+        validate_string(too_deep)
+    with pytest.raises(ValueError, match="nested too deeply"):
+        c.parse_str("-" * 100000 + "x")
+
+def test_calculator_init_update():
+    c = Calculator({"a": 1.0}, b=2.0)
+    assert c.items() == [("a", 1.0), ("b", 2.0)]
+    c.update([("c", 3)], a=0.5)
+    assert c.items() == [("a", 0.5), ("b", 2.0), ("c", 3.0)]
+    with pytest.raises(TypeError):
+        c.update({"d": 4.0, "e": "not a number"})
+    with pytest.raises(ValueError):
+        c.update({"f": 4.0, "1x": 1.0})
+    assert "d" not in c
+    assert "f" not in c
+    assert len(c) == 3
+
+def test_calculator_register_function():
+    c = Calculator()
+    c.set("t", 0.5)
+    c.register_function("envelope", lambda t, width: math.exp(-t**2 / width), 2)
+    assert c.parse_str("2*envelope(t, 4)") == 2 * math.exp(-0.25 / 4)
+    assert c.parse_get("envelope(t, 1)") == math.exp(-0.25)
+    with pytest.raises(ValueError):
+        c.parse_str("envelope(t)")
+    with pytest.raises(ValueError):
+        c.register_function("sin", math.sin, 1)
+
+    def failing(x):
+        raise KeyError("missing entry")
+
+    c.register_function("lookup", failing, 1)
+    with pytest.raises(KeyError) as error:
+        c.parse_str("lookup(t) + 1")
+    assert "lookup(t) + 1" in str(error.value)
+
+def test_calculator_scope():
+    c = Calculator(theta=0.1, x=1.0)
+    with c.scope(theta=0.3, phi=1.2) as scoped:
+        assert scoped is c
+        assert c["theta"] == 0.3
+        assert c.parse_str("theta + phi + x") == 0.3 + 1.2 + 1.0
+        with c.scope(phi=2.0):
+            assert c["phi"] == 2.0
+            c["phi"] = 3.0
+            assert c["phi"] == 3.0
+        assert c["phi"] == 1.2
+        c["x"] = 2.0
+        assert c.parse_str("y = theta * 2; theta = 0.4; y") == 0.6
+        with c.scope(phi=2.0):
+            assert c.parse_str("z = 1; y = 5; x = 3") == 3.0
+        assert c["theta"] == 0.4
+        assert c["y"] == 0.6
+        assert c["x"] == 2.0
+        assert "z" not in c
+    assert c.items() == [("theta", 0.1), ("x", 1.0)]
+    with pytest.raises(RuntimeError):
+        with c.scope(theta=0.5):
+            raise RuntimeError()
+    assert c["theta"] == 0.1
+
+def test_calculator_scope_restores_writes():
+    c = Calculator(x=1.0)
+    c.set("s", 2.0, sigma=0.1)
+    before = c.items()
+    with c.scope():
+        c.set("a", 1.0)
+        c.set("x", 5.0)
+        c.set("z", 1.0 + 2.0j)
+        c.set("s", 3.0)
+        c["b"] = 2.0
+        c.update(d=4.0)
+        c.define("e", "x * 2")
+        c.evaluate_definitions({"f": "x + 1"})
+        c.set_distribution("g", "normal", mean=0.0, std=1.0)
+        c.parse_str("h = 7")
+        assert c["e"] == 10.0
+    assert c.items() == before
+    assert c.uncertainties() == [("s", 0.1)]
+    assert c.distributions() == {}
+    assert c.definitions() == {}
+    changes = []
+    c.on_change(lambda name, old, new: changes.append((name, old, new)))
+    with c.scope(x=2.0):
+        c.set("y", 3.0)
+    assert changes == [("x", 1.0, 2.0), ("y", None, 3.0), ("x", 2.0, 1.0), ("y", 3.0, None)]
+
+def test_calculator_scope_order():
+    c = Calculator(x=1.0)
+    s1, s2, s3 = c.scope(x=2.0), c.scope(y=3.0), c.scope(z=4.0)
+    s1.__enter__()
+    s2.__enter__()
+    with pytest.raises(RuntimeError):
+        s1.__exit__(None, None, None)
+    assert c["x"] == 2.0 and c["y"] == 3.0
+    s3.__enter__()
+    with pytest.raises(RuntimeError):
+        s2.__exit__(None, None, None)
+    assert c["z"] == 4.0
+    s3.__exit__(None, None, None)
+    s2.__exit__(None, None, None)
+    assert "y" not in c and "z" not in c
+    s1.__exit__(None, None, None)
+    assert c.items() == [("x", 1.0)]
+    s1.__exit__(None, None, None)
+
+def test_calculator_copy_pickle_compare():
+    c = Calculator(a=1.0, b=2.0)
+    c.register_function("distance", math.hypot, 2)
+    c2 = copy.copy(c)
+    c3 = copy.deepcopy(c)
+    c4 = pickle.loads(pickle.dumps(c))
+    assert c == c2
+    assert c == c3
+    assert c == c4
+    assert c4.parse_str("distance(a, b)") == math.hypot(1.0, 2.0)
+    c2["a"] = 3.0
+    assert c != c2
+    assert c["a"] == 1.0
+    assert c != {"a": 1.0, "b": 2.0}
+    with c.scope(a=5.0):
+        c5 = copy.copy(c)
+    assert c5["a"] == 5.0
+    with pytest.raises(NotImplementedError):
+        c < c2
+
+def test_calculator_partial():
+    c = Calculator(a=2.0)
+    result = c.partial("a*theta + 3*(a - 1) / phi")
+    assert isinstance(result, CalculatorFloat)
+    assert result.value == "2 * theta + 3 / phi"
+    assert c.partial("sin(b) ^ (a + 1)").value == "sin(b) ^ 3"
+    assert c.partial("-(x + a)").value == "-(x + 2)"
+    assert c.partial("x + exp(1000)").value == "x + exp(1000)"
+    assert c.partial("x * 1e999").value == "x * 1e400"
+    assert c.partial("-x^2 + a").value == "((-x) ^ 2) + 2"
+    assert c.partial("-(x + a)^2").value == "(-(x + 2)) ^ 2"
+    assert c.partial("x * 1e-7 * a").value == "x * 1e-7 * 2"
+    for unportable in ["x > a", "x && a", "factorial(x)", "erf(x)", "_x + a"]:
+        with pytest.raises(ValueError, match="qoqo_calculator"):
+            c.partial(unportable)
+    c.set("big", float("inf"))
+    assert c.partial("x + big").value == "x + big"
+    c.set("big", 1.0)
+    assert c.partial("a * 2 + cos(0)") == 5.0
+    c.set("theta", 0.5)
+    c.set("phi", 3.0)
+    assert c.partial("a*theta + 3*(a - 1) / phi") == 2.0
+    with pytest.raises(ValueError):
+        c.partial("theta / (a - 2)")
+
+def test_calculator_script():
+    c = Calculator(f=2.0)
+    assert c.parse_str("omega = 2*pi*f; t_pi = pi/omega") == 0.25
+    assert c["omega"] == 4 * math.pi
+    assert c["t_pi"] == 0.25
+    assert c.parse_str("a = 3\nb = (a +\n 1)\n\na * b") == 12.0
+    assert c["b"] == 4.0
+    with pytest.raises(ValueError) as error:
+        c.parse_str("x = 1; y = x / 0; z = 2")
+    assert "Statement 2 (y = x / 0)" in str(error.value)
+    assert c["x"] == 1.0
+    assert "z" not in c
+    with pytest.raises(ValueError):
+        c.parse_str(" ; ")
+
+def test_calculator_parse_complex():
+    c = Calculator(a=2.0)
+    assert c.parse_complex("sqrt(-1)") == 1j
+    assert c.parse_complex("a + 3*i") == 2 + 3j
+    assert c.parse_complex("sqrt(-4) * sqrt(-4)") == -4
+    assert c.parse_complex(1 + 2j) == 1 + 2j
+    assert c.parse_complex(CalculatorComplex.from_pair("a", "a^2")) == 2 + 4j
+    assert c.parse_complex(CalculatorFloat("a*2")) == 4
+    assert np.isclose(c.parse_complex("exp(i*pi)"), -1)
+    c.register_function("double", lambda x: 2 * x, 1)
+    assert c.parse_complex("double(1 + i)") == 2 + 2j
+    with pytest.raises(ValueError):
+        c.parse_complex("floor(i)")
+    with pytest.raises(TypeError):
+        c.parse_complex(dict())
+
+def test_calculator_evaluate_definitions():
+    c = Calculator(f=0.5)
+    values = c.evaluate_definitions({"t": "pi/omega", "omega": "2*pi*f", "n": 3})
+    assert values == {"omega": math.pi, "t": 1.0, "n": 3.0}
+    assert c["t"] == 1.0
+    assert c["omega"] == math.pi
+    with pytest.raises(ValueError) as error:
+        c.evaluate_definitions({"a": "b + 1", "b": "c * 2", "c": "a", "d": "1"})
+    assert "a -> b -> c -> a" in str(error.value)
+    assert "d" not in c
+    with pytest.raises(ValueError):
+        c.evaluate_definitions({"x": "1", "y": "x / 0"})
+    assert "x" not in c
+
+def test_calculator_define():
+    c = Calculator(f=0.5)
+    c.define("omega", "2*pi*f")
+    c.define("t_pi", "pi/omega")
+    assert c["t_pi"] == 1.0
+    c["f"] = 1.0
+    assert c["t_pi"] == 0.5
+    assert c.parse_str("2*t_pi") == 1.0
+    assert c.keys() == ["f", "omega", "t_pi"]
+    assert c.definitions() == {"omega": "2*pi*f", "t_pi": "pi/omega"}
+    with c.scope(f=0.25):
+        assert c["t_pi"] == 2.0
+    with pytest.raises(ValueError):
+        c.define("f", "t_pi * 2")
+    c2 = pickle.loads(pickle.dumps(c))
+    assert c2 == c
+    assert c2["t_pi"] == 0.5
+    c.set("t_pi", 3.0)
+    assert c.definitions() == {"omega": "2*pi*f"}
+    assert c["t_pi"] == 3.0
+    del c["f"]
+    with pytest.raises(ValueError):
+        c["omega"]
+
+def test_calculator_resolver():
+    config = {"f": 2.0, "g": 3.0}
+    requested = []
+
+    def resolver(name):
+        requested.append(name)
+        return config.get(name)
+
+    c = Calculator()
+    c.set_resolver(resolver)
+    assert c.parse_str("f * g") == 6.0
+    assert "f" not in c
+    config["pi"] = 3.0
+    assert c.parse_str("2 * pi + e") == 2 * math.pi + math.e
+    assert c.parse_complex("pi * i") == complex(0.0, math.pi)
+    assert "pi" not in requested and "e" not in requested and "i" not in requested
+    with pytest.raises(ValueError):
+        c.parse_str("h + 1")
+    c.set_resolver(resolver, cache=True)
+    assert c.parse_str("f + f") == 4.0
+    assert requested.count("f") == 2
+    assert c["f"] == 2.0
+    c.set_resolver(None)
+    assert c.parse_str("f") == 2.0
+    with pytest.raises(ValueError):
+        c.parse_str("g")
+
+    def failing(name):
+        raise LookupError(name)
+
+    c.set_resolver(failing)
+    with pytest.raises(LookupError):
+        c.parse_str("missing")
+
+def test_calculator_constants():
+    assert Calculator.constant_sets() == ["codata2018", "natural", "units"]
+    c = Calculator()
+    c.load_constants("codata2018")
+    c.load_constants("units")
+    assert math.isclose(c.parse_str("hbar*2*pi"), c.parse_str("h"))
+    assert math.isclose(c.parse_str("2*GHz*ns"), 2.0)
+    assert c.constants()["e"] == 1.602176634e-19
+    assert "hbar" in c
+    assert "hbar" in c.keys()
+    assert len(c) == len(c.constants())
+    assert dict(c.items())["hbar"] == c["hbar"]
+    with pytest.raises(ValueError):
+        c.set("hbar", 1.0)
+    with pytest.raises(ValueError):
+        c["c"] = 1.0
+    with pytest.raises(TypeError):
+        del c["c"]
+    with pytest.raises(KeyError):
+        del c["unknown"]
+    with pytest.raises(ValueError):
+        c.parse_str("h = 2")
+    with pytest.raises(ValueError):
+        c.define("k_B", "2 * x")
+    with pytest.raises(ValueError):
+        c.load_constants("natural")
+    with pytest.raises(ValueError):
+        c.load_constants("unknown")
+    c2 = Calculator(m_e=1.0)
+    with pytest.raises(ValueError):
+        c2.load_constants("codata2018")
+    assert c2.constants() == {}
+    assert pickle.loads(pickle.dumps(c)) == c
+
+def test_calculator_sweep():
+    c = Calculator(a=2.0, theta=10.0)
+    theta = np.linspace(0, np.pi, 5)
+    phi = np.array([0.0, 0.5, 1.0])
+    result = c.sweep("a * sin(theta) * cos(phi)", theta=theta, phi=phi)
+    assert result.shape == (5, 3)
+    npt.assert_allclose(result, 2.0 * np.outer(np.sin(theta), np.cos(phi)))
+    assert c["theta"] == 10.0
+    assert "phi" not in c
+    assert c.sweep("a + 1").shape == ()
+    assert c.sweep("theta", theta=[]).shape == (0,)
+    with pytest.raises(ValueError):
+        c.sweep("theta * b", theta=theta)
+    with pytest.raises(TypeError):
+        c.sweep("theta", theta=np.ones((2, 2)))
+
+def test_calculator_on_change():
+    changes = []
+    c = Calculator(x=1.0)
+    handle = c.on_change(lambda name, old, new: changes.append((name, old, new)))
+    c.set("x", 2.0)
+    c.update(y=3.0)
+    c["x"] = 4.0
+    del c["y"]
+    assert changes == [("x", 1.0, 2.0), ("y", None, 3.0), ("x", 2.0, 4.0), ("y", 3.0, None)]
+    changes.clear()
+    with c.scope(x=5.0):
+        assert c["x"] == 5.0
+    assert changes == [("x", 4.0, 5.0), ("x", 5.0, 4.0)]
+    changes.clear()
+    c.unsubscribe(handle)
+    c.set("x", 6.0)
+    assert changes == []
+    with pytest.raises(ValueError):
+        c.unsubscribe(handle)
+    with pytest.raises(TypeError):
+        c.on_change(1.0)
+
+def test_calculator_on_change_access(tmp_path):
+    c = Calculator(x=1.0)
+    seen = []
+    c.on_change(lambda name, old, new: seen.append((name, new, c.get(name) if name in c else None)))
+    c["x"] = 2.0
+    c.parse_str("y = x + 1; z = y * 2")
+    c.evaluate_definitions({"w": "z + 1"})
+    path = tmp_path / "variables.json"
+    path.write_text('{"v": 5.0}')
+    c.load(path)
+    c.set_resolver(lambda name: 7.0 if name == "r" else None, cache=True)
+    assert c.parse_str("r + 1") == 8.0
+    del c["x"]
+    assert seen == [
+        ("x", 2.0, 2.0),
+        ("y", 3.0, 3.0),
+        ("z", 6.0, 6.0),
+        ("w", 7.0, 7.0),
+        ("v", 5.0, 5.0),
+        ("r", 7.0, 7.0),
+        ("x", None, None),
+    ]
+
+def test_calculator_mapping_slots():
+    c = Calculator()
+    seen = []
+    c.on_change(lambda name, old, new: seen.append((name, c.get(name) if name in c else None)))
+    c["x"] = 1.0
+    operator.setitem(c, "y", 2.0)
+    type(c).__setitem__(c, "z", 3.0)
+    Calculator.__delitem__(c, "z")
+    operator.delitem(c, "y")
+    assert seen == [("x", 1.0), ("y", 2.0), ("z", 3.0), ("z", None), ("y", None)]
+    with pytest.raises(KeyError):
+        del c["missing"]
+    with pytest.raises(TypeError):
+        c[1] = 2.0
+    with pytest.raises(ValueError):
+        c["1x"] = 2.0
+    c.on_change(lambda name, old, new: operator.setitem(c, "copy", new) if name == "x" else None)
+    c["x"] = 5.0
+    assert c["copy"] == 5.0
+
+def test_shared_calculator():
+    c = SharedCalculator({"x": 1.0}, y=2.0)
+    assert c.parse_str("x + y") == 3.0
+    assert c.parse_get(CalculatorFloat("2 * y")) == 4.0
+    assert c.parse_str("z = x * 3; z + 1") == 4.0
+    assert c["z"] == 3.0
+    assert len(c) == 3
+    assert c.remove("z") == 3.0
+    assert "z" not in c
+    assert c.get("z", 5.0) == 5.0
+    with pytest.raises(KeyError):
+        c.remove("z")
+    with pytest.raises(ValueError):
+        c.parse_str("w + 1")
+    copied = copy.copy(c)
+    copied.set("x", 10.0)
+    assert c["x"] == 1.0
+    assert pickle.loads(pickle.dumps(c)).variables() == c.variables()
+
+    errors = []
+
+    def evaluate(index):
+        try:
+            for _ in range(200):
+                c.set("t{}".format(index), float(index))
+                assert c.parse_str("t{} * 2 + x".format(index)) == 2.0 * index + 1.0
+        except Exception as err:
+            errors.append(err)
+
+    threads = [threading.Thread(target=evaluate, args=(i,)) for i in range(8)]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+    assert errors == []
+
+def test_calculator_files(tmp_path):
+    json_file = tmp_path / "parameters.json"
+    json_file.write_text('{"omega": "2 * pi * f", "f": 1.5, "n": 3}')
+    c = Calculator.from_file(json_file)
+    assert math.isclose(c["omega"], 3.0 * math.pi)
+    assert c["n"] == 3.0
+    toml_file = tmp_path / "parameters.toml"
+    toml_file.write_text('t = "n * dt"\ndt = 0.5\n')
+    assert c.load(str(toml_file)) == {"t": 1.5, "dt": 0.5}
+    c.define("double_t", "2 * t")
+    for name in ("saved.json", "saved.toml"):
+        c.save(tmp_path / name)
+        loaded = Calculator.from_file(tmp_path / name)
+        assert loaded.items() == c.items()
+        assert loaded.definitions() == {}
+        loaded = Calculator.from_file(tmp_path / name, derived=True)
+        assert loaded.items() == c.items()
+        assert loaded.definitions() == {"double_t": "2 * t"}
+        loaded["t"] = 2.0
+        assert loaded["double_t"] == 4.0
+    special = Calculator(up=math.inf, down=-math.inf, undefined=math.nan, x=1.0)
+    for name in ("special.json", "special.toml"):
+        special.save(tmp_path / name)
+        for derived in (False, True):
+            loaded = Calculator.from_file(tmp_path / name, derived=derived)
+            assert loaded["up"] == math.inf
+            assert loaded["down"] == -math.inf
+            assert math.isnan(loaded["undefined"])
+            assert loaded["x"] == 1.0
+            assert loaded.definitions() == {}
+    assert '"up": "inf"' in (tmp_path / "special.json").read_text()
+    broken = tmp_path / "broken.toml"
+    broken.write_text('a = "b + 1"\nc = 2\n')
+    with pytest.raises(ValueError) as err:
+        c.load(broken)
+    assert "broken.toml" in str(err.value)
+    assert "a = b + 1" in str(err.value)
+    assert "c" not in c
+    with pytest.raises(ValueError):
+        c.load(tmp_path / "parameters.yaml")
+    with pytest.raises(OSError):
+        c.load(tmp_path / "missing.json")
+    with pytest.raises(TypeError):
+        c.load(1)
+
+def test_calculator_conditional():
+    c = Calculator(x=2.0, a=1.0, b=-1.0)
+    assert c.parse_str("x > 0 ? a : b") == 1.0
+    assert c.parse_get(CalculatorFloat("x < 0 ? a : b")) == -1.0
+    assert c.parse_str("x >= 2 and x <= 2") == 1.0
+    assert c.parse_str("x == 2 && not (a != 1)") == 1.0
+    assert c.parse_str("x < 1 || b == -1") == 1.0
+    assert c.parse_str("!(x > 1)") == 0.0
+    assert c.parse_str("x > 0 ? x < 1 ? 1 : 2 : 3") == 2.0
+    assert c.parse_str("x == 0 && 1 / x > 0") == 0.0
+    assert c.parse_str("x > 1 ? 5 : 1 / 0") == 5.0
+    assert c.parse_str("min(x, a) + max(x, a)") == 3.0
+    assert c.parse_str("clamp(x, -1, 1.5)") == 1.5
+    assert c.parse_complex("sqrt(-1) == i") == 1.0
+    with pytest.raises(ValueError, match="qoqo_calculator"):
+        c.partial("y > 0 ? a : x")
+    with pytest.raises(ValueError):
+        c.parse_str("x > 0 ? a")
+    with pytest.raises(ValueError, match="reserved word"):
+        c.set("and", 1.0)
+    with pytest.raises(ValueError):
+        c["if"] = 1.0
+    with pytest.raises(ValueError):
+        Calculator(**{"or": 1.0})
+    with pytest.raises(ValueError):
+        c.parse_str("if = 1")
+
+def test_calculator_explain():
+    c = Calculator(x=2.0, y=3.0)
+    trace = c.explain("x * (y + 1)")
+    assert trace["value"] == 8.0
+    assert trace["variables"] == {"x": 2.0, "y": 3.0}
+    steps = [(step["expression"], step["value"]) for step in trace["steps"]]
+    assert steps == [("x", 2.0), ("y", 3.0), ("1", 1.0), ("(y + 1)", 4.0), ("x * (y + 1)", 8.0)]
+    assert trace["steps"][3]["start"] == 4
+    assert trace["steps"][3]["end"] == 11
+    assert trace["steps"][4]["kind"] == "binary"
+    trace = c.explain("x > 1 ? sin(0) : z")
+    assert [step["expression"] for step in trace["steps"]] == [
+        "x", "1", "x > 1", "0", "sin(0)", "x > 1 ? sin(0) : z"]
+    with pytest.raises(ValueError):
+        c.explain("z + 1")
+    c.set("z", 1.0 + 2.0j)
+    trace = c.explain("z*conj(z)")
+    assert trace["value"] == c.parse_str("z*conj(z)") == 5.0
+    assert trace["variables"] == {"z": 1.0 + 2.0j}
+    assert [step["value"] for step in trace["steps"]] == [1.0 + 2.0j, 1.0 + 2.0j, 1.0 - 2.0j, 5.0]
+    with pytest.raises(ValueError):
+        c.explain("z + 1")
+    trace = c.explain("w = x + 1; w * 2")
+    assert trace["value"] == 6.0
+    assert c["w"] == 3.0
+    assert trace["variables"] == {"x": 2.0, "w": 3.0}
+    steps = [(step["expression"], step["start"], step["value"]) for step in trace["steps"]]
+    assert steps == [
+        ("x", 4, 2.0), ("1", 8, 1.0), ("x + 1", 4, 3.0), ("w", 11, 3.0), ("2", 15, 2.0),
+        ("w * 2", 11, 6.0)]
+    with pytest.raises(ValueError, match="Statement 2"):
+        c.explain("w = 1; w / 0")
+
+def test_parse_string():
+    assert parse_string("1 + 2") == 3.0
+    assert parse_string("a*b", a=1, b=2) == 2.0
+    assert parse_string("a*b", {"a": 3.0}, b=2) == 6.0
+    with pytest.raises(ValueError):
+        parse_string("a*b", a=1)
+    assert parse_string_partial("a*b", a=1, b=2) == CalculatorFloat(2.0)
+    assert parse_string_partial("a*b + 1", a=2) == CalculatorFloat("2 * b + 1")
+    for expression in ["-x^2", "2*pi", "1 > 0 ? 1 : 2", "sign(0) + x!", "x^-1 + sqrt(x)"]:
+        value = Calculator(x=3).parse_str(expression)
+        assert parse_string(expression, x=3) == value
+        assert parse_string_partial(expression, x=3) == CalculatorFloat(value)
+        assert validate_string(expression)["variables"] <= {"x", "pi"}
+    assert parse_string("-x^2", x=3) == 9.0
+
+def test_calculator_validate():
+    c = Calculator()
+    c.register_function("f", lambda x: x, 1)
+    c.set("a", 1.0)
+    names = c.validate("a * sin(b) + f(c) * pi")
+    assert names == {
+        "variables": {"a", "b", "c", "pi"},
+        "functions": {"sin", "f"},
+        "unbound_variables": {"b", "c"},
+        "unknown_functions": set(),
+        "wrong_arguments": set(),
+    }
+    assert c.validate("x = 2 * y; x + z")["variables"] == {"y", "z"}
+    with pytest.raises(ValueError) as err:
+        c.validate("a * (b + ")
+    assert err.value.position == 9
+    names = c.validate("g(a) + foo(1) + f(1, 2) + sin(1, 2)")
+    assert names["unknown_functions"] == {"g", "foo"}
+    assert names["wrong_arguments"] == {"f", "sin"}
+    assert names["unbound_variables"] == set()
+    names = validate_string("a + b")
+    assert names["variables"] == names["unbound_variables"] == {"a", "b"}
+    assert validate_string("f(a)")["unknown_functions"] == {"f"}
+
+def test_calculator_complex_variables():
+    c = Calculator(x=2.0)
+    c.set("z", 1.0 + 2.0j)
+    c["w"] = 3.0j
+    assert c["z"] == 1.0 + 2.0j
+    assert c.get("w") == 3.0j
+    assert c.items() == [("w", 3.0j), ("x", 2.0), ("z", 1.0 + 2.0j)]
+    assert c.parse_complex("z * x + w") == 2.0 + 7.0j
+    assert c.parse_str("z * conj(z)") == 5.0
+    assert c.parse_str("real(z) + imag(w)") == 4.0
+    assert c.parse_str("abs(w)") == 3.0
+    with pytest.raises(ValueError) as err:
+        c.parse_str("z + x")
+    assert "not real" in str(err.value)
+    c.define("y", "z * w")
+    assert c["y"] == -6.0 + 3.0j
+    assert c.partial("z + x") == CalculatorFloat("z + 2")
+    with c.scope(z=1.0):
+        assert c.parse_str("z + x") == 3.0
+    c.set("z", 4.0)
+    assert c.parse_str("z + x") == 6.0
+    c.set("z", 1.0j)
+    assert pickle.loads(pickle.dumps(c)) == c
+    del c["z"]
+    assert "z" not in c
+    with pytest.raises(TypeError):
+        c.set("z", "text")
+    c = Calculator({"z": 1.0j}, x=2.0)
+    assert c.items() == [("x", 2.0), ("z", 1.0j)]
+    c.update(x=1.0 + 1.0j, z=3.0)
+    assert c.items() == [("x", 1.0 + 1.0j), ("z", 3.0)]
+    with c.scope(z=1.0):
+        with pytest.raises(ValueError):
+            c.update(x=0.0, z=2.0j)
+    assert c["x"] == 1.0 + 1.0j
+    with pytest.raises(TypeError):
+        c.update(x="text")
+
+def test_calculator_precision():
+    c = Calculator(x=1e-8)
+    assert c.precision() is None
+    assert c.parse_str("(1 - cos(x)) / x^2") == 0.0
+    c.set_precision(40)
+    assert c.precision() == 40
+    assert c.parse_str("(1 - cos(x)) / x^2") == 0.5
+    assert c.parse_str("0.1 + 0.2") == 0.3
+    assert c.parse_decimal("1 / 3") == "0.3333333333333333333333333333333333333333"
+    assert c.parse_decimal("pi", digits=30) == "3.14159265358979323846264338328"
+    c.set_resolver(lambda name: 3.0 if name in ("pi", "z") else None)
+    assert c.parse_decimal("pi", digits=30) == "3.14159265358979323846264338328"
+    assert c.parse_decimal("z") == "3"
+    c.set_resolver(None)
+    assert c.parse_decimal("(1 + 1e-30) - 1") == "1e-30"
+    c.define("y", "x^2")
+    assert c.parse_decimal("y") == "1e-16"
+    c.register_function("absolute", abs, 1)
+    with pytest.raises(ValueError):
+        c.parse_str("absolute(x)")
+    assert c.parse_str("parity(x + 3) * theta(x) + delta(0)") == 0.0
+    # Results that are nan or infinite in float precision raise in high precision
+    for expression in ["sqrt(-1)", "log(0)", "asin(2)", "atanh(1)", "(-8)^0.5", "exp(1e6)"]:
+        with pytest.raises(ValueError):
+            c.parse_str(expression)
+        c.set_precision(None)
+        assert not math.isfinite(c.parse_str(expression))
+        c.set_precision(40)
+    for expression in ["erf(x)", "tgamma(x)", "lgamma(x)", "factorial(x)", "x!"]:
+        with pytest.raises(ValueError, match="can not be evaluated in high precision"):
+            c.parse_str(expression)
+    assert pickle.loads(pickle.dumps(c)).precision() == 40
+    c.set_precision(None)
+    assert c.parse_str("0.1 + 0.2") == 0.1 + 0.2
+    with pytest.raises(ValueError):
+        c.parse_decimal("1 / 3")
+    with pytest.raises(ValueError):
+        c.set_precision(0)
+
+def test_calculator_sample():
+    c = Calculator(g=2.0)
+    c.set_distribution("f", "normal", mean=5.0, std=0.1)
+    c.set_distribution("t", "truncated_normal", mean=0.0, std=1.0, low=0.0, high=1.0)
+    c.set_distribution("u", "uniform", low=-1.0, high=1.0)
+    assert c["f"] == 5.0
+    assert c["u"] == 0.0
+    assert c.distributions()["f"] == ("normal", {"mean": 5.0, "std": 0.1})
+    samples = c.sample("f * g", 10000, seed=1)
+    assert samples.shape == (10000,)
+    assert abs(np.mean(samples) - 10.0) < 0.01
+    assert abs(np.std(samples) - 0.2) < 0.01
+    npt.assert_array_equal(samples, c.sample("f * g", 10000, seed=1))
+    bounded = c.sample("t", 1000, seed=2)
+    assert np.all((bounded >= 0.0) & (bounded <= 1.0))
+    c.set_distribution("z", "truncated_normal", mean=0.0, std=1e-200, low=1.0, high=2.0)
+    assert np.all(c.sample("z", 3, seed=1) == 1.0)
+    c.set_distribution("z", "truncated_normal", mean=0.0, std=1.0, low=1e300, high=1e301)
+    assert np.all(c.sample("z", 3, seed=1) >= 1e300)
+    c.define("h", "u^2")
+    assert np.all(c.sample("h", 1000, seed=3) <= 1.0)
+    assert np.all(c.sample("u <= 0 ? 0 : h / u", 1000, seed=3) >= 0.0)
+    with c.scope(f=1.0):
+        npt.assert_array_equal(c.sample("f", 3, seed=1), [1.0, 1.0, 1.0])
+    assert pickle.loads(pickle.dumps(c)) == c
+    c.set("f", 4.0)
+    assert "f" not in c.distributions()
+    with pytest.raises(ValueError):
+        c.set_distribution("f", "normal", mean=1.0)
+    with pytest.raises(ValueError):
+        c.set_distribution("f", "poisson", lam=1.0)
+    with pytest.raises(ValueError):
+        c.set_distribution("f", "uniform", low=-1e308, high=1e308)
+
+def test_calculator_uncertainty():
+    c = Calculator(g=2.0)
+    c.set("f", 5.0, sigma=0.01)
+    c.set("h", 3.0, sigma=0.02)
+    assert c["f"] == 5.0
+    assert c.uncertainties() == [("f", 0.01), ("h", 0.02)]
+    value, sigma = c.evaluate_with_uncertainty("g * f")
+    assert value == 10.0
+    assert abs(sigma - 0.02) < 1e-12
+    value, sigma = c.evaluate_with_uncertainty("f + h")
+    assert abs(sigma - np.sqrt(0.01**2 + 0.02**2)) < 1e-12
+    value, sigma = c.evaluate_with_uncertainty("f + h", correlation=[[1.0, 0.5], [0.5, 1.0]])
+    assert abs(sigma - np.sqrt(0.01**2 + 0.02**2 + 2 * 0.5 * 0.01 * 0.02)) < 1e-12
+    value, sigma = c.evaluate_with_uncertainty("f + h", correlation={("f", "h"): -1.0})
+    assert abs(sigma - 0.01) < 1e-12
+    with pytest.raises(ValueError):
+        c.evaluate_with_uncertainty("f", correlation={("f", "g"): 0.5})
+    c.set("j", 1.0, sigma=0.1)
+    with pytest.raises(ValueError):
+        c.evaluate_with_uncertainty(
+            "f", correlation={("f", "h"): 0.9, ("h", "j"): 0.9, ("f", "j"): -0.9}
+        )
+    c.set("j", 1.0)
+    c.define("k", "sin(f)")
+    value, sigma = c.evaluate_with_uncertainty("k")
+    assert abs(sigma - abs(np.cos(5.0)) * 0.01) < 1e-12
+    value, sigma = c.evaluate_with_uncertainty("lgamma(f)")
+    assert abs(sigma - 1.5061176684318 * 0.01) < 1e-8
+    with c.scope(f=1.0):
+        assert c.evaluate_with_uncertainty("f") == (1.0, 0.0)
+    assert pickle.loads(pickle.dumps(c)) == c
+    c.set("h", 4.0)
+    assert c.uncertainties() == [("f", 0.01)]
+    with pytest.raises(ValueError):
+        c.set("f", 5.0, sigma=-1.0)
+    with pytest.raises(ValueError):
+        c.evaluate_with_uncertainty("f", correlation=[[1.0, 0.5], [0.5, 1.0]])
+
+def test_calculator_cache():
+    c = Calculator(x=2.0)
+    assert c.cache_info() == {"hits": 0, "misses": 0, "size": 0, "maxsize": 256}
+    for _ in range(3):
+        assert c.parse_str("x * 2") == 4.0
+    c.set("x", 3.0)
+    assert c.parse_str("x * 2") == 6.0
+    assert c.cache_info() == {"hits": 3, "misses": 1, "size": 1, "maxsize": 256}
+    c.set_cache_size(2)
+    c.parse_str("x + 1")
+    c.parse_str("x * 2")
+    c.parse_str("x - 1")
+    assert c.cache_info()["size"] == 2
+    c.parse_str("x + 1")
+    assert c.cache_info()["misses"] == 4
+    with pytest.raises(ValueError):
+        c.parse_str("x +")
+    assert c.cache_info()["size"] == 2
+    assert pickle.loads(pickle.dumps(c)).cache_info()["maxsize"] == 2
+    c.clear_cache()
+    assert c.cache_info() == {"hits": 0, "misses": 0, "size": 0, "maxsize": 2}
+    c.set_cache_size(0)
+    c.parse_str("x * 2")
+    assert c.cache_info()["size"] == 0
+
+def test_calculator_mapping():
+    c = Calculator()
+    c["theta"] = 0.1
+    c.set("phi", 2)
+    assert c["theta"] == 0.1
+    assert "phi" in c
+    assert "psi" not in c
+    assert len(c) == 2
+    assert list(c) == ["phi", "theta"]
+    assert c.keys() == ["phi", "theta"]
+    assert c.values() == [2.0, 0.1]
+    assert c.items() == [("phi", 2.0), ("theta", 0.1)]
+    assert c.get("psi") is None
+    assert c.get("psi", 1.0) == 1.0
+    del c["phi"]
+    assert len(c) == 1
+    with pytest.raises(KeyError):
+        c["phi"]
+    with pytest.raises(KeyError):
+        del c["phi"]
+
 @pytest.mark.skip()
 @pytest.mark.parametrize("initial", [
     (1, 1, ),
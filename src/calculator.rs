@@ -15,54 +15,662 @@
 //! Converts the qoqo_calculator Calculator struct for parsing string expressions to floats
 //! into a Python class.
 
-use crate::convert_into_calculator_float;
-use pyo3::exceptions::{PyValueError, PyTypeError};
+use crate::constants;
+use crate::distribution::{self, Distribution};
+use crate::expression::{
+    self, EvaluationContext, Expression, ExpressionError, ExpressionKind, Statement, StatementKind,
+};
+use crate::expression_cache::{ExpressionCache, DEFAULT_CACHE_SIZE};
+use crate::precision::{self, Decimal, PrecisionContext};
+use crate::uncertainty::{self, GradientContext};
+use crate::variable_file;
+use crate::{
+    convert_into_calculator_complex, convert_into_calculator_float, CalculatorFloatWrapper,
+};
+use ndarray::{ArrayD, IxDyn};
+use num_complex::Complex;
+use numpy::{IntoPyArray, PyArray1, PyArrayDyn};
+use pyo3::class::basic::CompareOp;
+use pyo3::exceptions::{
+    PyKeyError, PyNotImplementedError, PyRuntimeError, PyTypeError, PyValueError,
+};
 use pyo3::prelude::*;
-use qoqo_calculator::Calculator;
+use pyo3::types::{PyDict, PyIterator, PyList, PyTuple, PyType};
+use pyo3::{
+    PyContextProtocol, PyIterProtocol, PyMappingProtocol, PyObjectProtocol, PySequenceProtocol,
+};
+use qoqo_calculator::{Calculator, CalculatorFloat};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+
+/// Python function registered for use in parsed expressions.
+#[derive(Debug, Clone)]
+struct RegisteredFunction {
+    function: PyObject,
+    arity: usize,
+}
+
+/// Float or complex value assigned to a variable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VariableValue {
+    Real(f64),
+    Complex(Complex<f64>),
+}
+
+impl VariableValue {
+    /// Convert a Python float or complex number, naming the variable in the error.
+    fn extract(name: &str, val: &PyAny) -> PyResult<Self> {
+        if let Ok(x) = val.extract::<f64>() {
+            Ok(VariableValue::Real(x))
+        } else if let Ok(z) = val.extract::<Complex<f64>>() {
+            Ok(VariableValue::Complex(z))
+        } else {
+            Err(PyTypeError::new_err(format!(
+                "Value of variable {} can not be converted to float or complex",
+                name
+            )))
+        }
+    }
+
+    /// Return the value as Python float or complex number.
+    fn to_object(self, py: Python) -> PyObject {
+        match self {
+            VariableValue::Real(x) => x.to_object(py),
+            VariableValue::Complex(z) => z.to_object(py),
+        }
+    }
+}
+
+/// Name, old value and new value of a changed variable.
+type VariableChange = (String, Option<PyObject>, Option<PyObject>);
+
+/// Evaluated node of a syntax tree together with its float or complex value.
+type ValueStep<'a> = (&'a Expression, VariableValue);
+
+/// Variable whose value is recomputed from an expression whenever it is read.
+#[derive(Debug, Clone)]
+struct DerivedVariable {
+    source: String,
+    expression: Expression,
+}
+
+/// Variables of a Calculator saved when a scope is entered and restored when it exits.
+#[derive(Debug, Clone)]
+struct ScopeState {
+    id: usize,
+    variables: HashMap<String, f64>,
+    complex_variables: HashMap<String, Complex<f64>>,
+    derived: HashMap<String, DerivedVariable>,
+    distributions: HashMap<String, Distribution>,
+    uncertainties: HashMap<String, f64>,
+    scopes: Vec<HashMap<String, f64>>,
+}
 
 #[pyclass(name = "Calculator", module = "qoqo_calculator_pyo3")]
+#[derive(Clone, Debug)]
 pub struct CalculatorWrapper {
     pub r_calculator: Calculator,
+    functions: HashMap<String, RegisteredFunction>,
+    scopes: Vec<HashMap<String, f64>>,
+    /// States saved by the scopes entered with `Calculator.scope`, innermost last.
+    scope_states: Vec<ScopeState>,
+    next_scope: usize,
+    derived: HashMap<String, DerivedVariable>,
+    resolver: Option<PyObject>,
+    cache_resolved: bool,
+    constants: HashMap<String, f64>,
+    complex_variables: HashMap<String, Complex<f64>>,
+    observers: Vec<(usize, PyObject)>,
+    next_observer: usize,
+    /// Variable changes not yet passed to the observers.
+    changes: Vec<VariableChange>,
+    precision: Option<usize>,
+    distributions: HashMap<String, Distribution>,
+    uncertainties: HashMap<String, f64>,
+    expression_cache: ExpressionCache,
 }
 #[pymethods]
 impl CalculatorWrapper {
     /// Create new Python instance of CalculatorWrapper.
     ///
+    /// # Arguments
+    ///
+    /// * `variables` - Optional mapping or iterable of (name, value) pairs of initial variables,
+    ///                 values can be float or complex
+    /// * `kwargs` - Initial variables given as keyword arguments
+    ///
     /// # Returns
     ///
-    /// `<Self>` - CalculatorWrapper instance of Calculator
+    /// `PyResult<Self>` - CalculatorWrapper instance of Calculator or corresponding Python error
     ///
     #[new]
-    fn new() -> Self {
-        let r_calculator = Calculator::new();
-        CalculatorWrapper { r_calculator }
+    #[args(variables = "None", kwargs = "**")]
+    fn new(variables: Option<&PyAny>, kwargs: Option<&PyDict>) -> PyResult<Self> {
+        let mut r_calculator = Calculator::new();
+        let mut complex_variables = HashMap::new();
+        for (name, val) in extract_values(variables, kwargs)? {
+            match val {
+                VariableValue::Real(x) => {
+                    complex_variables.remove(&name);
+                    r_calculator.set_variable(&name, x);
+                }
+                VariableValue::Complex(z) => {
+                    r_calculator.variables.remove(&name);
+                    complex_variables.insert(name, z);
+                }
+            }
+        }
+        Ok(CalculatorWrapper {
+            r_calculator,
+            functions: HashMap::new(),
+            scopes: Vec::new(),
+            scope_states: Vec::new(),
+            next_scope: 0,
+            derived: HashMap::new(),
+            resolver: None,
+            cache_resolved: false,
+            constants: HashMap::new(),
+            complex_variables,
+            observers: Vec::new(),
+            next_observer: 0,
+            changes: Vec::new(),
+            precision: None,
+            distributions: HashMap::new(),
+            uncertainties: HashMap::new(),
+            expression_cache: ExpressionCache::new(DEFAULT_CACHE_SIZE),
+        })
+    }
+
+    /// Create Python copy of CalculatorWrapper.
+    ///
+    /// Variables bound in active scopes are copied as plain variables.
+    ///
+    /// # Returns
+    ///
+    /// `CalculatorWrapper` - copy of the Calculator without active scopes
+    ///
+    fn __copy__(&self) -> CalculatorWrapper {
+        self.flattened()
+    }
+
+    /// Create Python deep copy of CalculatorWrapper.
+    ///
+    /// # Returns
+    ///
+    /// `CalculatorWrapper` - copy of the Calculator without active scopes
+    ///
+    fn __deepcopy__(&self, _memodict: Py<PyAny>) -> CalculatorWrapper {
+        self.flattened()
+    }
+
+    /// Get new arguments for Python of CalculatorWrapper.
+    ///
+    /// # Returns
+    ///
+    /// `((HashMap<String, f64>,), HashMap<String, String>)` - variables of the Calculator
+    ///
+    fn __getnewargs_ex__(&self) -> ((HashMap<String, f64>,), HashMap<String, String>) {
+        ((self.variables(),), HashMap::new())
+    }
+
+    /// Get the Python state of CalculatorWrapper that is not covered by __getnewargs_ex__.
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<PyObject>` - dictionary of the registered functions with their arity,
+    ///                        the expressions of derived variables, the resolver,
+    ///                        the loaded constants, the complex variables,
+    ///                        the number of digits of the high-precision mode,
+    ///                        the distributions of random variables,
+    ///                        the uncertainties of variables
+    ///                        and the size of the expression cache
+    ///
+    fn __getstate__(&self) -> PyResult<PyObject> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        let functions: HashMap<String, (PyObject, usize)> = self
+            .functions
+            .iter()
+            .map(|(name, f)| (name.clone(), (f.function.clone(), f.arity)))
+            .collect();
+        let state = PyDict::new(py);
+        state.set_item("functions", functions)?;
+        state.set_item("definitions", self.definitions())?;
+        state.set_item("resolver", self.resolver.clone())?;
+        state.set_item("cache_resolved", self.cache_resolved)?;
+        state.set_item("constants", self.constants.clone())?;
+        state.set_item("complex_variables", self.complex_variables.clone())?;
+        state.set_item("precision", self.precision)?;
+        state.set_item("distributions", self.distributions())?;
+        state.set_item("uncertainties", self.uncertainties.clone())?;
+        state.set_item("cache_size", self.expression_cache.capacity())?;
+        Ok(state.into())
+    }
+
+    /// Restore the Python state of CalculatorWrapper created by __getstate__.
+    ///
+    /// Entries missing from the state keep their default, so states written
+    /// by older versions can still be restored.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - dictionary created by __getstate__
+    ///
+    fn __setstate__(&mut self, state: &PyDict) -> PyResult<()> {
+        let functions: HashMap<String, (PyObject, usize)> = state_item(state, "functions")?;
+        let definitions: HashMap<String, String> = state_item(state, "definitions")?;
+        let distributions: HashMap<String, (String, HashMap<String, f64>)> =
+            state_item(state, "distributions")?;
+        let cache_size: Option<usize> = state_item(state, "cache_size")?;
+        self.expression_cache
+            .set_capacity(cache_size.unwrap_or(DEFAULT_CACHE_SIZE));
+        self.precision = state_item(state, "precision")?;
+        self.uncertainties = state_item(state, "uncertainties")?;
+        for (name, (kind, parameters)) in distributions {
+            let distribution = Distribution::from_parameters(&kind, &parameters)?;
+            self.distributions.insert(name, distribution);
+        }
+        self.resolver = state_item(state, "resolver")?;
+        self.cache_resolved = state_item(state, "cache_resolved")?;
+        self.constants = state_item(state, "constants")?;
+        self.complex_variables = state_item(state, "complex_variables")?;
+        self.functions = functions
+            .into_iter()
+            .map(|(name, (function, arity))| (name, RegisteredFunction { function, arity }))
+            .collect();
+        for (name, source) in definitions {
+            self.define(&name, &source)?;
+        }
+        Ok(())
     }
 
     /// Set variable for Calculator.
     ///
+    /// Complex values are stored as complex variables. Real evaluations using them
+    /// raise a ValueError when the result is not real. Setting a variable without
+    /// `sigma` removes its uncertainty.
+    ///
+    /// The name must be a valid identifier. The reserved words `and`, `or`, `not` and `if`
+    /// are rejected since the expression grammar supports logical operators.
+    ///
+    /// # Arguments
+    ///
+    /// * `variable_string` - string of the variable name
+    /// * `val` - Float or complex value of the variable
+    /// * `sigma` - standard deviation of a float variable used by `evaluate_with_uncertainty`
+    ///
+    #[args(sigma = "None")]
+    fn set(
+        slf: &PyCell<Self>,
+        variable_string: &str,
+        val: &PyAny,
+        sigma: Option<f64>,
+    ) -> PyResult<()> {
+        Self::observed(slf, |calculator| {
+            let sigma = match sigma {
+                Some(sigma) => sigma,
+                None => return calculator.set_value(variable_string, val),
+            };
+            if !(sigma.is_finite() && sigma >= 0.0) {
+                return Err(PyValueError::new_err(format!(
+                    "Uncertainty of variable {} must be a non-negative number",
+                    variable_string
+                )));
+            }
+            if val.extract::<f64>().is_err() {
+                return Err(PyTypeError::new_err(format!(
+                    "Variable {} with uncertainty must be a float",
+                    variable_string
+                )));
+            }
+            if calculator
+                .scopes
+                .iter()
+                .any(|layer| layer.contains_key(variable_string))
+            {
+                return Err(PyValueError::new_err(format!(
+                    "Can not set uncertainty for variable {} bound in a scope",
+                    variable_string
+                )));
+            }
+            calculator.set_value(variable_string, val)?;
+            calculator
+                .uncertainties
+                .insert(variable_string.to_string(), sigma);
+            Ok(())
+        })
+    }
+
+    /// Return the uncertainties of all variables set with `sigma`.
+    ///
+    /// The order of the variables is the order of the rows and columns of the
+    /// correlation matrix of `evaluate_with_uncertainty`.
+    ///
+    /// # Returns
+    ///
+    /// `Vec<(String, f64)>` - names and standard deviations sorted by name
+    ///
+    fn uncertainties(&self) -> Vec<(String, f64)> {
+        let mut uncertainties: Vec<(String, f64)> = self
+            .uncertainties
+            .iter()
+            .map(|(name, sigma)| (name.clone(), *sigma))
+            .collect();
+        uncertainties.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+        uncertainties
+    }
+
+    /// Evaluate a string expression and its standard deviation by linear error propagation.
+    ///
+    /// The standard deviation is computed from the gradient of the expression with respect
+    /// to the variables set with `sigma`. Variables bound in an active scope count as exact.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Expression that is evaluated
+    /// * `correlation` - correlation coefficients of the variables, either a dict mapping pairs
+    ///                   of variable names to coefficients, where missing pairs are uncorrelated,
+    ///                   or a full matrix over all variables with uncertainty in the order of
+    ///                   `uncertainties()`; the variables are uncorrelated when None
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<(f64, f64)>` - value and standard deviation of the expression
+    ///
+    #[args(correlation = "None")]
+    fn evaluate_with_uncertainty(
+        slf: &PyCell<Self>,
+        input: &str,
+        correlation: Option<&PyAny>,
+    ) -> PyResult<(f64, f64)> {
+        Self::observed(slf, |calculator| {
+            let (variables, sigmas): (Vec<String>, Vec<f64>) = calculator
+                .uncertainties()
+                .into_iter()
+                .map(|(name, sigma)| {
+                    if calculator
+                        .scopes
+                        .iter()
+                        .any(|layer| layer.contains_key(&name))
+                    {
+                        (name, 0.0)
+                    } else {
+                        (name, sigma)
+                    }
+                })
+                .unzip();
+            let factor = correlation
+                .map(|correlation| correlation_factor(correlation, &variables))
+                .transpose()?;
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            let (value, gradient) = calculator
+                .with_context(py, |context| {
+                    let parsed = expression::parse(input)?;
+                    expression::check_functions(&parsed, context)?;
+                    uncertainty::evaluate_gradient(&parsed, context, &variables)
+                })
+                .map_err(|err| expression_error_to_py(py, err, input))?;
+            let sigma = uncertainty::propagate(&gradient, &sigmas, factor.as_deref());
+            Ok((value, sigma))
+        })
+    }
+
+    /// Set several variables of the Calculator at once.
+    ///
+    /// All names and values are validated before any variable is set,
+    /// so the Calculator is left unchanged when one of the entries is invalid.
+    ///
+    /// # Arguments
+    ///
+    /// * `variables` - Optional mapping or iterable of (name, value) pairs,
+    ///                 values can be float or complex
+    /// * `kwargs` - Variables given as keyword arguments
+    ///
+    #[args(variables = "None", kwargs = "**")]
+    fn update(
+        slf: &PyCell<Self>,
+        variables: Option<&PyAny>,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<()> {
+        Self::observed(slf, |calculator| {
+            let variables = extract_values(variables, kwargs)?;
+            for (name, val) in variables.iter() {
+                calculator.check_assignable(name, *val)?;
+            }
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            for (name, val) in variables {
+                calculator.store_observed(py, &name, val);
+            }
+            Ok(())
+        })
+    }
+
+    /// Create a context manager binding temporary variables.
+    ///
+    /// While the scope is active its variables shadow variables of the same name.
+    /// On exit the variables of the Calculator are restored to their state on entering,
+    /// undoing all changes made inside the scope with `set`, `update`, `parse_str`,
+    /// `define`, `evaluate_definitions`, `set_distribution` and item assignment.
+    /// Scopes can be nested and must be exited in the reverse order of entering them.
+    ///
+    /// # Arguments
+    ///
+    /// * `variables` - Optional mapping or iterable of (name, value) pairs
+    /// * `kwargs` - Temporary variables given as keyword arguments
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<CalculatorScopeWrapper>` - context manager activating the bindings
+    ///
+    #[args(variables = "None", kwargs = "**")]
+    fn scope(
+        slf: &PyCell<Self>,
+        variables: Option<&PyAny>,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<CalculatorScopeWrapper> {
+        let bindings: HashMap<String, f64> =
+            extract_variables(variables, kwargs)?.into_iter().collect();
+        for name in bindings.keys() {
+            slf.borrow().check_writable(name)?;
+        }
+        Ok(CalculatorScopeWrapper {
+            calculator: slf.into(),
+            bindings,
+            active: None,
+        })
+    }
+
+    /// Define a derived variable that is recomputed from an expression whenever it is read.
+    ///
+    /// A plain variable of the same name is replaced. Setting the variable with `set`
+    /// turns it back into a plain variable.
+    ///
+    /// # Arguments
+    ///
+    /// * `variable_string` - string of the variable name
+    /// * `input` - Expression defining the variable
+    ///
+    fn define(&mut self, variable_string: &str, input: &str) -> PyResult<()> {
+        let derived_variables =
+            self.derived_variables(&[(variable_string.to_string(), input.to_string())])?;
+        for (name, derived) in derived_variables {
+            self.insert_derived(name, derived);
+        }
+        Ok(())
+    }
+
+    /// Return the expressions of all derived variables.
+    ///
+    /// # Returns
+    ///
+    /// `HashMap<String, String>` - expressions of the derived variables by name
+    ///
+    fn definitions(&self) -> HashMap<String, String> {
+        self.derived
+            .iter()
+            .map(|(name, derived)| (name.clone(), derived.source.clone()))
+            .collect()
+    }
+
+    /// Get the value of a variable of the Calculator.
+    ///
     /// # Arguments
     ///
     /// * `variable_string` - string of the variable name
-    /// * `val` - Float value of the variable
+    /// * `default` - Value returned when the variable is not set
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<Option<PyObject>>` - float or complex value of the variable or the default
+    ///
+    #[args(default = "None")]
+    fn get(&self, variable_string: &str, default: Option<PyObject>) -> PyResult<Option<PyObject>> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        match self.lookup_object(py, variable_string) {
+            Some(value) => value.map(Some),
+            None => Ok(default),
+        }
+    }
+
+    /// Return the names of all variables set in the Calculator.
+    ///
+    /// Loaded constants are included.
+    ///
+    /// # Returns
+    ///
+    /// `Vec<String>` - sorted variable names
+    ///
+    fn keys(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.variables().into_keys().collect();
+        names.extend(
+            self.derived
+                .keys()
+                .chain(self.complex_variables.keys())
+                .filter(|name| self.variable(name).is_none())
+                .chain(self.constants.keys())
+                .cloned(),
+        );
+        names.sort();
+        names
+    }
+
+    /// Return the values of all variables set in the Calculator.
     ///
-    fn set(&mut self, variable_string: &str, val: f64) {
-        self.r_calculator.set_variable(variable_string, val);
+    /// # Returns
+    ///
+    /// `PyResult<Vec<PyObject>>` - float or complex variable values, ordered by variable name
+    ///
+    fn values(&self) -> PyResult<Vec<PyObject>> {
+        Ok(self
+            .sorted_items()?
+            .into_iter()
+            .map(|(_, val)| val)
+            .collect())
+    }
+
+    /// Return all (name, value) pairs of variables set in the Calculator.
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<Vec<(String, PyObject)>>` - variable names and float or complex values,
+    ///                                       ordered by variable name
+    ///
+    fn items(&self) -> PyResult<Vec<(String, PyObject)>> {
+        self.sorted_items()
+    }
+
+    /// Set the float or complex value of a variable.
+    ///
+    /// Defined as method instead of mapping protocol slot, so the observers are
+    /// called after the Calculator is released. The module initialisation connects
+    /// this method and `__delitem__` to the mapping slots of the type, so `c[key] = value`,
+    /// `del c[key]` and `operator.setitem` call them.
+    fn __setitem__(slf: &PyCell<Self>, key: String, value: &PyAny) -> PyResult<()> {
+        validate_variable_name(&key)?;
+        Self::observed(slf, |calculator| calculator.set_value(&key, value))
+    }
+
+    /// Remove a variable, raising KeyError when it is not set
+    /// and TypeError when it is a read-only constant.
+    fn __delitem__(slf: &PyCell<Self>, key: String) -> PyResult<()> {
+        Self::observed(slf, |calculator| {
+            if calculator.constants.contains_key(&key) {
+                return Err(PyTypeError::new_err(format!(
+                    "Can not delete constant {}",
+                    key
+                )));
+            }
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            let old = calculator.visible_value(py, &key);
+            if calculator.remove_variable(&key) {
+                let new = calculator.visible_value(py, &key);
+                if !calculator.observers.is_empty() {
+                    calculator.changes.push((key, old, new));
+                }
+                Ok(())
+            } else {
+                Err(PyKeyError::new_err(key))
+            }
+        })
     }
 
     ///  Parse a string expression.
     ///
+    /// The input can contain several statements separated by `;` or newlines.
+    /// Statements of the form `name = expression` set the variable `name`,
+    /// the value of the last statement is returned.
+    ///
     /// # Arguments
     ///
     /// * `input` - Expression that is parsed
     ///
-    pub fn parse_str(&mut self, input: &str) -> PyResult<f64> {
-        match self.r_calculator.parse_str(input) {
-            Ok(x) => Ok(x),
-            Err(x) => Err(PyValueError::new_err(format!(
-                "{:?}; expression: {}",
-                x, input
-            ))),
-        }
+    pub fn parse_str(slf: &PyCell<Self>, input: &str) -> PyResult<f64> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        Self::observed(slf, |calculator| calculator.evaluate_str(py, input))
+    }
+
+    /// Set the number of parsed expressions kept in the cache of the Calculator.
+    ///
+    /// `parse_str` and `parse_get` look up the parsed form of an expression string in
+    /// a least recently used cache, so repeated evaluations skip parsing.
+    /// The least recently used expressions are evicted when the cache is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - maximal number of cached expressions, 0 disables the cache
+    ///
+    fn set_cache_size(&mut self, size: usize) {
+        self.expression_cache.set_capacity(size);
+    }
+
+    /// Remove all parsed expressions from the cache and reset its statistics.
+    fn clear_cache(&mut self) {
+        self.expression_cache.clear();
+    }
+
+    /// Return the statistics of the cache of parsed expressions.
+    ///
+    /// # Returns
+    ///
+    /// `HashMap<&str, usize>` - number of `hits` and `misses` since the last `clear_cache`,
+    ///                          the current `size` and the `maxsize` of the cache
+    ///
+    fn cache_info(&self) -> HashMap<&'static str, usize> {
+        let (hits, misses, size) = self.expression_cache.statistics();
+        let mut info = HashMap::new();
+        info.insert("hits", hits);
+        info.insert("misses", misses);
+        info.insert("size", size);
+        info.insert("maxsize", self.expression_cache.capacity());
+        info
     }
 
     /// Parse an input to float.
@@ -71,31 +679,2182 @@ impl CalculatorWrapper {
     ///
     /// * `input` - Parsed string CalculatorFloat or returns float value
     ///
-    pub fn parse_get(&mut self, input: &PyAny) -> PyResult<f64> {
-        let converted = convert_into_calculator_float(input).map_err(|_| {
-            PyTypeError::new_err("Input can not be converted to Calculator Float")
-        })?;
-        let out = self.r_calculator.parse_get(converted);
-        match out {
-            Ok(x) => Ok(x),
-            Err(x) => Err(PyValueError::new_err(format!("{:?}", x))),
+    pub fn parse_get(slf: &PyCell<Self>, input: &PyAny) -> PyResult<f64> {
+        Self::observed(slf, |calculator| {
+            let converted = convert_into_calculator_float(input).map_err(|_| {
+                PyTypeError::new_err("Input can not be converted to Calculator Float")
+            })?;
+            match converted {
+                CalculatorFloat::Float(x) => Ok(x),
+                CalculatorFloat::Str(x) => {
+                    let gil = pyo3::Python::acquire_gil();
+                    let py = gil.python();
+                    calculator.evaluate_str(py, &x)
+                }
+            }
+        })
+    }
+
+    /// Parse an input to complex.
+    ///
+    /// Expressions are evaluated with complex arithmetic, so intermediate results
+    /// like `sqrt(-1)` can be complex. The imaginary unit is available as `i`
+    /// unless a variable of that name is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - string expression, CalculatorComplex, CalculatorFloat or complex number
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<Complex<f64>>` - value of the input or corresponding Python error
+    ///
+    fn parse_complex(slf: &PyCell<Self>, input: &PyAny) -> PyResult<Complex<f64>> {
+        Self::observed(slf, |calculator| {
+            let converted = convert_into_calculator_complex(input).map_err(|_| {
+                PyTypeError::new_err("Input can not be converted to Calculator Complex")
+            })?;
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            let real = calculator.evaluate_complex_part(py, converted.re)?;
+            let imaginary = calculator.evaluate_complex_part(py, converted.im)?;
+            Ok(real + Complex::new(0.0, 1.0) * imaginary)
+        })
+    }
+
+    /// Enable or disable the high-precision mode of the Calculator.
+    ///
+    /// In high-precision mode `parse_str`, `parse_get` and all other real evaluations
+    /// compute with decimal numbers of the given number of significant digits and round
+    /// the result to float. Numeric literals are used exactly as written and float variables
+    /// with their shortest decimal representation, so `0.1` is exactly 0.1.
+    /// Registered Python functions and complex variables can not be used in high-precision mode.
+    ///
+    /// Decimal numbers have no nan or infinity, so high-precision mode raises a ValueError
+    /// where float evaluation returns one of them:
+    /// * arguments outside the real domain of a function, like `sqrt(-1)`, `log(0)`,
+    ///   `asin(2)`, `atanh(1)` or `(-8)^0.5`
+    /// * results outside of the range 1e-10000 to 1e10000, like `exp(1e6)`
+    ///
+    /// The functions `erf`, `tgamma`, `lgamma` and `factorial` (also written `x!`)
+    /// have no high-precision implementation and raise a ValueError as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `digits` - number of significant digits, or None to evaluate with float precision
+    ///
+    fn set_precision(&mut self, digits: Option<usize>) -> PyResult<()> {
+        if digits == Some(0) {
+            return Err(PyValueError::new_err("Number of digits must be positive"));
         }
+        self.precision = digits;
+        Ok(())
     }
-}
 
-///  Parse a string expression.
-///
-/// # Arguments
-///
-/// * `expression` - Expression that is parsed
-///
-pub fn parse_str(expression: &str) -> PyResult<f64> {
-    let mut calculator = Calculator::new();
-    match calculator.parse_str(expression) {
-        Ok(x) => Ok(x),
-        Err(x) => Err(PyValueError::new_err(format!(
-            "{:?}; expression {}",
-            x, expression
-        ))),
+    /// Return the number of digits of the high-precision mode.
+    ///
+    /// # Returns
+    ///
+    /// `Option<usize>` - number of significant digits, or None when the mode is disabled
+    ///
+    fn precision(&self) -> Option<usize> {
+        self.precision
+    }
+
+    /// Evaluate a string expression in high precision and return the result as decimal string.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Expression that is evaluated
+    /// * `digits` - number of significant digits, defaults to the digits set with `set_precision`
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<String>` - value of the expression rounded to `digits` significant digits
+    ///
+    #[args(digits = "None")]
+    fn parse_decimal(slf: &PyCell<Self>, input: &str, digits: Option<usize>) -> PyResult<String> {
+        Self::observed(slf, |calculator| {
+            let digits = match digits.or(calculator.precision) {
+                Some(0) => return Err(PyValueError::new_err("Number of digits must be positive")),
+                Some(digits) => digits,
+                None => {
+                    return Err(PyValueError::new_err(
+                        "Number of digits not given and high-precision mode is disabled",
+                    ))
+                }
+            };
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            calculator
+                .with_context(py, |context| {
+                    let parsed = expression::parse(input)?;
+                    expression::check_functions(&parsed, context)?;
+                    let value = precision::evaluate(&parsed, input, context, digits)?;
+                    Ok(value.to_decimal_string(digits))
+                })
+                .map_err(|err| expression_error_to_py(py, err, input))
+        })
+    }
+
+    /// Evaluate named expressions that can reference each other.
+    ///
+    /// The definitions are evaluated in dependency order and the results are stored
+    /// as variables. When one of the definitions fails no variable is changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `definitions` - mapping of variable names to expressions or numbers
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<HashMap<String, f64>>` - values of all definitions
+    ///
+    fn evaluate_definitions(
+        slf: &PyCell<Self>,
+        definitions: &PyDict,
+    ) -> PyResult<HashMap<String, f64>> {
+        Self::observed(slf, |calculator| {
+            let mut converted: Vec<(String, CalculatorFloat)> =
+                Vec::with_capacity(definitions.len());
+            for (name, value) in definitions.iter() {
+                let name: String = name
+                    .extract()
+                    .map_err(|_| PyTypeError::new_err("Variable names must be strings"))?;
+                let value = convert_into_calculator_float(value).map_err(|_| {
+                    PyTypeError::new_err(format!(
+                        "Definition of {} can not be converted to Calculator Float",
+                        name
+                    ))
+                })?;
+                converted.push((name, value));
+            }
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            calculator.apply_definitions(py, converted)
+        })
+    }
+
+    /// Evaluate a string expression and return a trace of all evaluated sub-expressions.
+    ///
+    /// The input can contain several statements like in `parse_str`, assignments set
+    /// their variable before the next statement is evaluated.
+    /// The returned dictionary contains the keys
+    /// * `value` - value of the expression, the value of the last statement for scripts
+    /// * `steps` - list of evaluated sub-expressions in evaluation order, each a dictionary
+    ///             with the `expression` source text, its `start` and `end` position in
+    ///             the input, the `kind` of the sub-expression and its `value`
+    /// * `variables` - values of all variables that were looked up
+    ///
+    /// Expressions referencing complex variables are evaluated with complex arithmetic
+    /// like in `parse_str`, the values of the steps are then float or complex numbers.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Expression that is evaluated
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<PyObject>` - dictionary containing the trace of the evaluation
+    ///
+    fn explain(slf: &PyCell<Self>, input: &str) -> PyResult<PyObject> {
+        Self::observed(slf, |calculator| {
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            let statements = expression::parse_script(input)
+                .map_err(|err| expression_error_to_py(py, err, input))?;
+            let multiple_statements = statements.len() > 1;
+            let step_list = PyList::empty(py);
+            let variables = PyDict::new(py);
+            let mut value = None;
+            for (index, statement) in statements.iter().enumerate() {
+                let (result, steps) = calculator.trace_statement(py, statement).map_err(|err| {
+                    let err = if multiple_statements {
+                        statement_error(err, input, index, statement)
+                    } else {
+                        err
+                    };
+                    expression_error_to_py(py, err, input)
+                })?;
+                for (node, value) in steps {
+                    let step = PyDict::new(py);
+                    step.set_item("expression", &input[node.start..node.end])?;
+                    step.set_item("start", node.start)?;
+                    step.set_item("end", node.end)?;
+                    step.set_item("kind", node.kind_name())?;
+                    step.set_item("value", value.to_object(py))?;
+                    step_list.append(step)?;
+                    if let ExpressionKind::Variable(name) = &node.kind {
+                        variables.set_item(name, value.to_object(py))?;
+                    }
+                }
+                value = Some(result);
+            }
+            let trace = PyDict::new(py);
+            trace.set_item("value", value)?;
+            trace.set_item("steps", step_list)?;
+            trace.set_item("variables", variables)?;
+            Ok(trace.into())
+        })
+    }
+
+    /// Check a string expression without evaluating it.
+    ///
+    /// The expression is parsed and all called functions are checked to exist in the
+    /// Calculator with the right number of arguments. Variables do not need to be set.
+    /// Problems are collected in the returned report, only syntax errors raise.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Expression that is checked
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<PyObject>` - dictionary with the sets of referenced `variables` and `functions`,
+    ///                        the `unbound_variables` without a value in the Calculator,
+    ///                        the `unknown_functions` and the functions called with
+    ///                        `wrong_arguments`; ValueError with the `position` of syntax errors
+    ///
+    fn validate(&self, input: &str) -> PyResult<PyObject> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        let context = CalculatorContext::new(py, self);
+        let statements = expression::parse_script(input)
+            .map_err(|err| expression_error_to_py(py, err, input))?;
+        let mut unknown_functions = HashSet::new();
+        let mut wrong_arguments = HashSet::new();
+        for statement in statements.iter() {
+            for err in expression::invalid_calls(statement.expression(), &context) {
+                match err {
+                    ExpressionError::FunctionNotFound { name } => {
+                        unknown_functions.insert(name);
+                    }
+                    ExpressionError::WrongNumberOfArguments { name, .. } => {
+                        wrong_arguments.insert(name);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        let (variables, functions) = expression::referenced_names(&statements);
+        let unbound_variables: HashSet<String> = variables
+            .iter()
+            .filter(|name| {
+                !self.__contains__(name.to_string()) && expression::builtin_constant(name).is_none()
+            })
+            .cloned()
+            .collect();
+        let names = PyDict::new(py);
+        names.set_item(
+            "variables",
+            variables.into_iter().collect::<HashSet<String>>(),
+        )?;
+        names.set_item(
+            "functions",
+            functions.into_iter().collect::<HashSet<String>>(),
+        )?;
+        names.set_item("unbound_variables", unbound_variables)?;
+        names.set_item("unknown_functions", unknown_functions)?;
+        names.set_item("wrong_arguments", wrong_arguments)?;
+        Ok(names.into())
+    }
+
+    /// Create a Calculator with the variables of a JSON or TOML file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - path of the file, see `Calculator.load`
+    /// * `derived` - keep expressions as derived variables, see `Calculator.load`
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<CalculatorWrapper>` - Calculator containing the variables of the file
+    ///
+    #[staticmethod]
+    #[args(derived = "false")]
+    fn from_file(path: &PyAny, derived: bool) -> PyResult<CalculatorWrapper> {
+        let mut calculator = CalculatorWrapper::new(None, None)?;
+        calculator.load_file(path, derived)?;
+        Ok(calculator)
+    }
+
+    /// Load the variables of a JSON or TOML file into the Calculator.
+    ///
+    /// The file contains a table mapping variable names to numbers or expression strings.
+    /// The JSON strings `inf`, `-inf` and `nan` are read as numbers like in TOML.
+    /// Expressions are evaluated in dependency order and can use variables of the Calculator.
+    /// By default expressions are stored as plain variables holding their value,
+    /// with `derived=True` they are stored as derived variables, so a file written
+    /// by `Calculator.save` is restored unchanged.
+    /// When one of the variables fails no variable is changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - path of the file, the format is determined by the extension .json or .toml
+    /// * `derived` - store expressions as derived variables instead of their values
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<HashMap<String, f64>>` - values of all variables of the file
+    ///
+    #[args(derived = "false")]
+    fn load(slf: &PyCell<Self>, path: &PyAny, derived: bool) -> PyResult<HashMap<String, f64>> {
+        Self::observed(slf, |calculator| calculator.load_file(path, derived))
     }
+
+    /// Save the variables of the Calculator to a JSON or TOML file.
+    ///
+    /// Plain variables are written as numbers, derived variables as their expressions,
+    /// load the file with `derived=True` to restore the derived variables.
+    /// Variables bound in active scopes are saved as plain variables, constants are not saved.
+    /// Random variables are saved with their nominal value, uncertainties are not saved.
+    /// Calculators containing complex variables can not be saved. Infinite and nan values
+    /// are written as `inf`, `-inf` and `nan` in TOML and as the same strings in JSON,
+    /// so they are restored when the file is loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - path of the file, the format is determined by the extension .json or .toml
+    ///
+    fn save(&self, path: &PyAny) -> PyResult<()> {
+        let path = extract_path(path)?;
+        if let Some(name) = self.complex_variables.keys().min() {
+            return Err(PyValueError::new_err(format!(
+                "Complex variable {} can not be saved to a file",
+                name
+            )));
+        }
+        variable_file::write_definitions(&path, &self.variables(), &self.definitions())
+    }
+
+    /// Set a resolver that is called for variables unknown to the Calculator.
+    ///
+    /// The resolver is called with the name of the missing variable and returns
+    /// its value, or None when it can not provide the variable.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolver` - Python callable, or None to remove the current resolver
+    /// * `cache` - store resolved values as variables of the Calculator
+    ///
+    #[args(cache = "false")]
+    fn set_resolver(&mut self, resolver: &PyAny, cache: bool) -> PyResult<()> {
+        if resolver.is_none() {
+            self.resolver = None;
+        } else if resolver.is_callable() {
+            self.resolver = Some(resolver.into());
+        } else {
+            return Err(PyTypeError::new_err("Resolver is not callable"));
+        }
+        self.cache_resolved = cache;
+        Ok(())
+    }
+
+    /// Partially evaluate a string expression.
+    ///
+    /// Known variables are substituted and all constant sub-expressions are folded to numbers.
+    /// The remaining expression is printed so that qoqo_calculator reads it the same way.
+    /// A ValueError is raised when it still contains a construct qoqo_calculator can not read,
+    /// such as a conditional, a comparison or a user defined function.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Expression that is partially evaluated
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<PyObject>` - float when all variables are known, otherwise
+    ///                        a CalculatorFloat containing the remaining symbolic expression
+    ///
+    fn partial(slf: &PyCell<Self>, input: &str) -> PyResult<PyObject> {
+        Self::observed(slf, |calculator| {
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            match calculator.simplify_str(py, input)? {
+                CalculatorFloat::Float(x) => Ok(x.to_object(py)),
+                simplified => Ok(CalculatorFloatWrapper {
+                    cf_internal: simplified,
+                }
+                .into_py(py)),
+            }
+        })
+    }
+
+    /// Evaluate an expression on the Cartesian grid of several parameter arrays.
+    ///
+    /// The expression is parsed once and evaluated for every combination of the
+    /// parameter values. The parameters shadow variables of the same name during the sweep,
+    /// all other variables of the Calculator are used as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Expression that is evaluated
+    /// * `kwargs` - One-dimensional arrays of values for each swept parameter
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<Py<PyArrayDyn<f64>>>` - NumPy array with one axis per parameter
+    ///                                   in the order of the keyword arguments
+    ///
+    #[args(kwargs = "**")]
+    fn sweep(
+        slf: &PyCell<Self>,
+        input: &str,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<Py<PyArrayDyn<f64>>> {
+        Self::observed(slf, |calculator| {
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            let mut parameters: Vec<(String, Vec<f64>)> = Vec::new();
+            if let Some(kwargs) = kwargs {
+                for (name, values) in kwargs.iter() {
+                    let name: String = name.extract()?;
+                    validate_variable_name(&name)?;
+                    calculator.check_writable(&name)?;
+                    let values: Vec<f64> = values.extract().map_err(|_| {
+                        PyTypeError::new_err(format!(
+                            "Values of parameter {} are not a one-dimensional array of floats",
+                            name
+                        ))
+                    })?;
+                    parameters.push((name, values));
+                }
+            }
+            let parsed =
+                expression::parse(input).map_err(|err| expression_error_to_py(py, err, input))?;
+            calculator.scopes.push(HashMap::new());
+            let result = calculator.evaluate_grid(py, &parsed, input, &parameters);
+            calculator.scopes.pop();
+            let values = result.map_err(|err| expression_error_to_py(py, err, input))?;
+            let shape: Vec<usize> = parameters.iter().map(|(_, values)| values.len()).collect();
+            let array = ArrayD::from_shape_vec(IxDyn(&shape), values)
+                .map_err(|err| PyValueError::new_err(err.to_string()))?;
+            Ok(array.into_pyarray(py).to_owned())
+        })
+    }
+
+    /// Define a variable as random variable with a probability distribution.
+    ///
+    /// The supported distributions and their parameters are `normal` (mean, std),
+    /// `uniform` (low, high) and `truncated_normal` (mean, std, low, high).
+    /// Outside of `sample` the variable has its nominal value: the mean of a normal
+    /// distribution, the mean clamped to [low, high] for a truncated normal distribution
+    /// and the center of a uniform distribution. Setting the variable with `set`
+    /// turns it back into a plain variable.
+    ///
+    /// # Arguments
+    ///
+    /// * `variable_string` - string of the variable name
+    /// * `distribution` - name of the distribution
+    /// * `kwargs` - parameters of the distribution
+    ///
+    #[args(kwargs = "**")]
+    fn set_distribution(
+        slf: &PyCell<Self>,
+        variable_string: &str,
+        distribution: &str,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<()> {
+        Self::observed(slf, |calculator| {
+            validate_variable_name(variable_string)?;
+            calculator.check_writable(variable_string)?;
+            if calculator
+                .scopes
+                .iter()
+                .any(|layer| layer.contains_key(variable_string))
+            {
+                return Err(PyValueError::new_err(format!(
+                    "Can not set distribution for variable {} bound in a scope",
+                    variable_string
+                )));
+            }
+            let parameters: HashMap<String, f64> = match kwargs {
+                Some(kwargs) => kwargs.extract()?,
+                None => HashMap::new(),
+            };
+            let distribution = Distribution::from_parameters(distribution, &parameters)?;
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            let value = VariableValue::Real(distribution.nominal_value());
+            calculator.store_observed(py, variable_string, value);
+            calculator
+                .distributions
+                .insert(variable_string.to_string(), distribution);
+            Ok(())
+        })
+    }
+
+    /// Return the distributions of all random variables.
+    ///
+    /// # Returns
+    ///
+    /// `HashMap<String, (String, HashMap<String, f64>)>` - name and parameters
+    ///                                                    of the distribution by variable
+    ///
+    fn distributions(&self) -> HashMap<String, (String, HashMap<String, f64>)> {
+        self.distributions
+            .iter()
+            .map(|(name, distribution)| {
+                (
+                    name.clone(),
+                    (
+                        distribution.kind().to_string(),
+                        distribution
+                            .parameters()
+                            .into_iter()
+                            .map(|(parameter, value)| (parameter.to_string(), value))
+                            .collect(),
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    /// Evaluate a string expression for random draws of the random variables.
+    ///
+    /// Every evaluation draws new values for the random variables the expression depends on,
+    /// directly or through derived variables. Random variables bound in an active scope
+    /// keep the value of the scope. The results are reproducible for a given seed.
+    ///
+    /// The expression is parsed once and evaluated for all draws at once in float precision,
+    /// a precision set with `set_precision` is not used. Expressions referencing complex
+    /// variables are evaluated draw by draw.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Expression that is evaluated
+    /// * `n` - number of evaluations
+    /// * `seed` - seed of the random number generator, a random seed is used when None
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<Py<PyArray1<f64>>>` - one-dimensional array with the `n` values
+    ///
+    #[args(seed = "None")]
+    fn sample(
+        slf: &PyCell<Self>,
+        input: &str,
+        n: usize,
+        seed: Option<u64>,
+    ) -> PyResult<Py<PyArray1<f64>>> {
+        Self::observed(slf, |calculator| {
+            let gil = pyo3::Python::acquire_gil();
+            let py = gil.python();
+            let parsed =
+                expression::parse(input).map_err(|err| expression_error_to_py(py, err, input))?;
+            let random_variables: Vec<(String, Distribution)> = calculator
+                .dependencies(&parsed)
+                .into_iter()
+                .filter(|name| {
+                    !calculator
+                        .scopes
+                        .iter()
+                        .any(|layer| layer.contains_key(name))
+                })
+                .filter_map(|name| {
+                    let distribution = *calculator.distributions.get(&name)?;
+                    Some((name, distribution))
+                })
+                .collect();
+            let mut rng = match seed {
+                Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+                None => ChaCha8Rng::from_entropy(),
+            };
+            let draws = random_variables
+                .iter()
+                .map(|(name, distribution)| {
+                    let draws = (0..n)
+                        .map(|_| distribution.sample(&mut rng))
+                        .collect::<PyResult<Vec<f64>>>()?;
+                    Ok((name.clone(), draws))
+                })
+                .collect::<PyResult<HashMap<String, Vec<f64>>>>()?;
+            let values = if calculator.references_complex(&parsed) {
+                calculator.scopes.push(HashMap::new());
+                let values = (0..n)
+                    .map(|index| {
+                        if let Some(layer) = calculator.scopes.last_mut() {
+                            for (name, draws) in draws.iter() {
+                                layer.insert(name.clone(), draws[index]);
+                            }
+                        }
+                        calculator.evaluate_real(py, &parsed, input)
+                    })
+                    .collect();
+                calculator.scopes.pop();
+                values
+            } else {
+                calculator.with_context(py, |context| {
+                    expression::check_functions(&parsed, context)?;
+                    uncertainty::evaluate_draws(&parsed, context, &draws, n)
+                })
+            }
+            .map_err(|err| expression_error_to_py(py, err, input))?;
+            Ok(values.into_pyarray(py).to_owned())
+        })
+    }
+
+    /// Return the names of the distributions supported by `set_distribution`.
+    ///
+    /// # Returns
+    ///
+    /// `Vec<&'static str>` - names of the supported distributions
+    ///
+    #[staticmethod]
+    fn distribution_names() -> Vec<&'static str> {
+        distribution::distribution_names()
+    }
+
+    /// Register a callback that is called whenever a variable is changed.
+    ///
+    /// The callback is called with the name, the old and the new value of the variable
+    /// after `set`, `update`, `set_distribution`, item assignment and deletion,
+    /// assignments in `parse_str`, `evaluate_definitions`, `load`, values cached from
+    /// the resolver, and when a scope is entered or exited. The old or new value is None
+    /// when the variable is not set. The callbacks are called after the operation
+    /// finished and can access the Calculator.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - Python callable taking the name, old and new value
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<usize>` - handle to unsubscribe the callback
+    ///
+    fn on_change(&mut self, callback: &PyAny) -> PyResult<usize> {
+        if !callback.is_callable() {
+            return Err(PyTypeError::new_err("Callback is not callable"));
+        }
+        let handle = self.next_observer;
+        self.next_observer += 1;
+        self.observers.push((handle, callback.into()));
+        Ok(handle)
+    }
+
+    /// Remove a callback registered with `on_change`.
+    ///
+    /// # Arguments
+    ///
+    /// * `handle` - handle returned by `on_change`
+    ///
+    fn unsubscribe(&mut self, handle: usize) -> PyResult<()> {
+        let len = self.observers.len();
+        self.observers.retain(|(h, _)| *h != handle);
+        if self.observers.len() == len {
+            Err(PyValueError::new_err(format!(
+                "No callback with handle {}",
+                handle
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Register a Python function that can be called in parsed expressions.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name under which the function is called in expressions
+    /// * `function` - Python callable taking `arity` floats and returning a float
+    /// * `arity` - number of arguments of the function
+    ///
+    fn register_function(&mut self, name: &str, function: &PyAny, arity: usize) -> PyResult<()> {
+        validate_variable_name(name)?;
+        if expression::builtin_arity(name).is_some() {
+            return Err(PyValueError::new_err(format!(
+                "Can not overwrite built-in function {}",
+                name
+            )));
+        }
+        if !function.is_callable() {
+            return Err(PyTypeError::new_err(format!(
+                "Function {} is not callable",
+                name
+            )));
+        }
+        self.functions.insert(
+            name.to_string(),
+            RegisteredFunction {
+                function: function.into(),
+                arity,
+            },
+        );
+        Ok(())
+    }
+
+    /// Load a set of physical constants as read-only variables.
+    ///
+    /// Loaded constants can not be set, deleted or redefined.
+    ///
+    /// # Arguments
+    ///
+    /// * `set_name` - name of the constant set, see `Calculator.constant_sets()`
+    ///
+    fn load_constants(&mut self, set_name: &str) -> PyResult<()> {
+        let constant_set = constants::constant_set(set_name).ok_or_else(|| {
+            PyValueError::new_err(format!(
+                "Unknown constant set {}; available sets: {}",
+                set_name,
+                constants::constant_set_names().join(", ")
+            ))
+        })?;
+        for (name, value) in constant_set.iter() {
+            let conflicting_constant = self
+                .constants
+                .get(*name)
+                .is_some_and(|loaded| loaded != value);
+            if conflicting_constant
+                || self.variable(name).is_some()
+                || self.derived.contains_key(*name)
+                || self.complex_variables.contains_key(*name)
+            {
+                return Err(PyValueError::new_err(format!(
+                    "Constant {} of set {} conflicts with an existing variable",
+                    name, set_name
+                )));
+            }
+        }
+        self.constants.extend(
+            constant_set
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value)),
+        );
+        Ok(())
+    }
+
+    /// Return the constants loaded into the Calculator.
+    ///
+    /// # Returns
+    ///
+    /// `HashMap<String, f64>` - names and values of the loaded constants
+    ///
+    fn constants(&self) -> HashMap<String, f64> {
+        self.constants.clone()
+    }
+
+    /// Return the names of the constant sets that can be loaded.
+    ///
+    /// # Returns
+    ///
+    /// `Vec<&'static str>` - names of the available constant sets
+    ///
+    #[staticmethod]
+    fn constant_sets() -> Vec<&'static str> {
+        constants::constant_set_names()
+    }
+}
+
+impl CalculatorWrapper {
+    /// Return an error when the variable is a read-only constant.
+    fn check_writable(&self, name: &str) -> PyResult<()> {
+        if self.constants.contains_key(name) {
+            Err(PyValueError::new_err(
+                ExpressionError::ReadOnlyVariable {
+                    name: name.to_string(),
+                }
+                .to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Load the variables of a JSON or TOML file, see `Calculator.load`.
+    fn load_file(&mut self, path: &PyAny, derived: bool) -> PyResult<HashMap<String, f64>> {
+        let path = extract_path(path)?;
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        let definitions = variable_file::read_definitions(&path)?;
+        let with_path = |err: PyErr| {
+            PyErr::from_type(
+                err.ptype(py),
+                format!("{}: {}", path.display(), err.instance(py)),
+            )
+        };
+        let expressions: Vec<(String, String)> = if derived {
+            definitions
+                .iter()
+                .filter_map(|(name, value)| match value {
+                    CalculatorFloat::Str(source) => Some((name.clone(), source.clone())),
+                    CalculatorFloat::Float(_) => None,
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let derived_variables = self.derived_variables(&expressions).map_err(with_path)?;
+        let values = self.apply_definitions(py, definitions).map_err(with_path)?;
+        for (name, derived) in derived_variables {
+            self.insert_derived(name, derived);
+        }
+        Ok(values)
+    }
+
+    /// Parse a script and evaluate it with the variables and functions of the Calculator.
+    ///
+    /// Assignments are stored in the Calculator and the value of the last statement is returned.
+    fn evaluate_str(&mut self, py: Python, input: &str) -> PyResult<f64> {
+        let statements = self
+            .expression_cache
+            .parse_script(input)
+            .map_err(|err| expression_error_to_py(py, err, input))?;
+        let multiple_statements = statements.len() > 1;
+        let mut value = None;
+        for (index, statement) in statements.iter().enumerate() {
+            let result = self
+                .evaluate_statement(py, statement, input)
+                .map_err(|err| {
+                    let err = if multiple_statements {
+                        statement_error(err, input, index, statement)
+                    } else {
+                        err
+                    };
+                    expression_error_to_py(py, err, input)
+                })?;
+            value = Some(result);
+        }
+        value.ok_or_else(|| expression_error_to_py(py, ExpressionError::EmptyScript, input))
+    }
+
+    /// Partially evaluate an expression to a float or the remaining symbolic expression.
+    fn simplify_str(&mut self, py: Python, input: &str) -> PyResult<CalculatorFloat> {
+        let simplified = self
+            .with_context(py, |context| {
+                let parsed = expression::parse(input)?;
+                expression::check_functions(&parsed, context)?;
+                let simplified = expression::simplify(&parsed, context)?;
+                if !matches!(simplified.kind, ExpressionKind::Number(_)) {
+                    expression::check_portable(&simplified, context)?;
+                }
+                Ok(simplified)
+            })
+            .map_err(|err| expression_error_to_py(py, err, input))?;
+        match simplified.kind {
+            ExpressionKind::Number(x) => Ok(CalculatorFloat::Float(x)),
+            _ => Ok(CalculatorFloat::from(simplified.to_string())),
+        }
+    }
+
+    /// Evaluate a single statement, storing the value of an assignment in the Calculator.
+    fn evaluate_statement(
+        &mut self,
+        py: Python,
+        statement: &Statement,
+        source: &str,
+    ) -> Result<f64, ExpressionError> {
+        let value = self.evaluate_real(py, statement.expression(), source)?;
+        self.store_assignment(py, statement, value)?;
+        Ok(value)
+    }
+
+    /// Store the value of a statement in the Calculator when the statement is an assignment.
+    fn store_assignment(
+        &mut self,
+        py: Python,
+        statement: &Statement,
+        value: f64,
+    ) -> Result<(), ExpressionError> {
+        if let StatementKind::Assignment { name, .. } = &statement.kind {
+            if self.constants.contains_key(name) {
+                return Err(ExpressionError::ReadOnlyVariable { name: name.clone() });
+            }
+            self.store_observed(py, name, VariableValue::Real(value));
+        }
+        Ok(())
+    }
+
+    /// Evaluate a single statement and record the value of every evaluated node,
+    /// storing the value of an assignment in the Calculator.
+    fn trace_statement<'a>(
+        &mut self,
+        py: Python,
+        statement: &'a Statement,
+    ) -> Result<(f64, Vec<ValueStep<'a>>), ExpressionError> {
+        let parsed = statement.expression();
+        let complex = self.references_complex(parsed);
+        let (value, steps) = self.with_context(py, |context| {
+            expression::check_functions(parsed, context)?;
+            if !complex {
+                let (value, steps) = expression::trace(parsed, context)?;
+                let steps: Vec<ValueStep> = steps
+                    .into_iter()
+                    .map(|(node, value)| (node, VariableValue::Real(value)))
+                    .collect();
+                return Ok((value, steps));
+            }
+            let (value, steps) = expression::trace_complex(parsed, context)?;
+            if value.im != 0.0 {
+                return Err(ExpressionError::NonRealResult { value });
+            }
+            let steps = steps
+                .into_iter()
+                .map(|(node, value)| {
+                    if value.im == 0.0 {
+                        (node, VariableValue::Real(value.re))
+                    } else {
+                        (node, VariableValue::Complex(value))
+                    }
+                })
+                .collect();
+            Ok((value.re, steps))
+        })?;
+        self.store_assignment(py, statement, value)?;
+        Ok((value, steps))
+    }
+
+    /// Evaluate an expression to a float.
+    ///
+    /// Expressions depending on complex variables are evaluated with complex arithmetic
+    /// and fail when the result is not real. In high-precision mode the expression is
+    /// evaluated with decimal numbers, reading numeric literals from `source`.
+    fn evaluate_real(
+        &mut self,
+        py: Python,
+        parsed: &Expression,
+        source: &str,
+    ) -> Result<f64, ExpressionError> {
+        let complex = self.references_complex(parsed);
+        let digits = self.precision;
+        self.with_context(py, |context| {
+            expression::check_functions(parsed, context)?;
+            if let Some(digits) = digits {
+                precision::evaluate(parsed, source, context, digits).map(|value| value.to_f64())
+            } else if complex {
+                let value = expression::evaluate_complex(parsed, context)?;
+                if value.im == 0.0 {
+                    Ok(value.re)
+                } else {
+                    Err(ExpressionError::NonRealResult { value })
+                }
+            } else {
+                expression::evaluate(parsed, context)
+            }
+        })
+    }
+
+    /// Return the value of a complex variable unless it is shadowed by a scope.
+    fn complex_value(&self, name: &str) -> Option<Complex<f64>> {
+        if self.scopes.iter().any(|layer| layer.contains_key(name)) {
+            None
+        } else {
+            self.complex_variables.get(name).copied()
+        }
+    }
+
+    /// Return a derived variable unless it is shadowed by a constant, plain or complex variable.
+    fn visible_derived(&self, name: &str) -> Option<&DerivedVariable> {
+        if self.constants.contains_key(name)
+            || self.variable(name).is_some()
+            || self.complex_value(name).is_some()
+        {
+            None
+        } else {
+            self.derived.get(name)
+        }
+    }
+
+    /// Return true when a variable is complex or derived from a complex variable.
+    fn is_complex(&self, name: &str) -> bool {
+        if self.constants.contains_key(name) || self.variable(name).is_some() {
+            return false;
+        }
+        if self.complex_value(name).is_some() {
+            return true;
+        }
+        self.derived
+            .get(name)
+            .is_some_and(|derived| self.references_complex(&derived.expression))
+    }
+
+    /// Return the variables an expression depends on, directly or through derived variables.
+    fn dependencies(&self, parsed: &Expression) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        let mut pending: Vec<&Expression> = vec![parsed];
+        while let Some(current) = pending.pop() {
+            for name in current.variable_names() {
+                if let Some(derived) = self.derived.get(&name) {
+                    if !names.contains(&name) {
+                        pending.push(&derived.expression);
+                    }
+                }
+                names.insert(name);
+            }
+        }
+        names
+    }
+
+    /// Return true when an expression references complex variables.
+    fn references_complex(&self, parsed: &Expression) -> bool {
+        parsed
+            .variable_names()
+            .iter()
+            .any(|name| self.is_complex(name))
+    }
+
+    /// Set a float or complex variable and record the change for the observers.
+    fn set_value(&mut self, name: &str, val: &PyAny) -> PyResult<()> {
+        validate_variable_name(name)?;
+        let val = VariableValue::extract(name, val)?;
+        self.check_assignable(name, val)?;
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        self.store_observed(py, name, val);
+        Ok(())
+    }
+
+    /// Check that a value can be stored in a variable with `store_value`.
+    fn check_assignable(&self, name: &str, val: VariableValue) -> PyResult<()> {
+        self.check_writable(name)?;
+        if let VariableValue::Complex(_) = val {
+            if self.scopes.iter().any(|layer| layer.contains_key(name)) {
+                return Err(PyValueError::new_err(format!(
+                    "Can not set complex value for variable {} bound in a scope",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a float or complex value checked with `check_assignable`.
+    fn store_value(&mut self, name: &str, val: VariableValue) {
+        match val {
+            VariableValue::Real(x) => self.assign_variable(name, x),
+            VariableValue::Complex(z) => {
+                self.derived.remove(name);
+                self.distributions.remove(name);
+                self.uncertainties.remove(name);
+                self.r_calculator.variables.remove(name);
+                self.complex_variables.insert(name.to_string(), z);
+            }
+        }
+    }
+
+    /// Store a value with `store_value` and record the change for the observers.
+    fn store_observed(&mut self, py: Python, name: &str, val: VariableValue) {
+        if self.observers.is_empty() {
+            self.store_value(name, val);
+            return;
+        }
+        let old = self.visible_value(py, name);
+        self.store_value(name, val);
+        self.changes
+            .push((name.to_string(), old, Some(val.to_object(py))));
+    }
+
+    /// Run a method of the Calculator and notify the observers of the recorded changes.
+    ///
+    /// The observers are called after the Calculator is released, so they can access it.
+    fn observed<T>(slf: &PyCell<Self>, f: impl FnOnce(&mut Self) -> PyResult<T>) -> PyResult<T> {
+        let (result, changes, observers) = {
+            let mut calculator = slf.try_borrow_mut()?;
+            let result = f(&mut calculator);
+            let changes = std::mem::take(&mut calculator.changes);
+            (result, changes, calculator.observers.clone())
+        };
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        let notified = notify_observers(py, &observers, &changes);
+        let value = result?;
+        notified?;
+        Ok(value)
+    }
+
+    /// Return the visible value of a variable, returning None when it is unknown or fails.
+    fn visible_value(&self, py: Python, name: &str) -> Option<PyObject> {
+        self.lookup_object(py, name).and_then(Result::ok)
+    }
+
+    /// Return the float or complex value of a variable, returning None when it is unknown.
+    fn lookup_object(&self, py: Python, name: &str) -> Option<PyResult<PyObject>> {
+        let context = CalculatorContext::new(py, self);
+        let value = if self.is_complex(name) {
+            context
+                .known_complex_variable(name)?
+                .map(|value| value.to_object(py))
+        } else {
+            context
+                .known_variable(name)?
+                .map(|value| value.to_object(py))
+        };
+        Some(value.map_err(|err| expression_error_to_py(py, err, name)))
+    }
+
+    /// Run `f` with an evaluation context of the Calculator.
+    ///
+    /// Variables provided by the resolver during `f` are stored in the Calculator
+    /// when caching of resolved variables is enabled.
+    fn with_context<T>(&mut self, py: Python, f: impl FnOnce(&CalculatorContext) -> T) -> T {
+        let (result, resolved) = {
+            let context = CalculatorContext::new(py, self);
+            let result = f(&context);
+            (result, context.resolved.into_inner())
+        };
+        if self.cache_resolved {
+            for (name, value) in resolved {
+                self.store_observed(py, &name, VariableValue::Real(value));
+            }
+        }
+        result
+    }
+
+    /// Return the value of a plain variable, taking active scopes into account.
+    fn variable(&self, name: &str) -> Option<f64> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|layer| layer.get(name).copied())
+            .or_else(|| self.r_calculator.variables.get(name).copied())
+    }
+
+    /// Return all plain variables visible in the Calculator, taking active scopes into account.
+    fn variables(&self) -> HashMap<String, f64> {
+        let mut variables = self.r_calculator.variables.clone();
+        for layer in self.scopes.iter() {
+            variables.extend(layer.iter().map(|(name, val)| (name.clone(), *val)));
+        }
+        variables
+    }
+
+    /// Set a variable in the innermost scope binding it, or in the Calculator itself.
+    ///
+    /// Setting a derived variable outside of a scope turns it into a plain variable.
+    fn assign_variable(&mut self, name: &str, val: f64) {
+        match self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find(|layer| layer.contains_key(name))
+        {
+            Some(layer) => {
+                layer.insert(name.to_string(), val);
+            }
+            None => {
+                self.derived.remove(name);
+                self.complex_variables.remove(name);
+                self.distributions.remove(name);
+                self.uncertainties.remove(name);
+                self.r_calculator.set_variable(name, val)
+            }
+        }
+    }
+
+    /// Remove a variable from the innermost scope binding it, or from the Calculator itself.
+    ///
+    /// Returns true when a variable was removed.
+    fn remove_variable(&mut self, name: &str) -> bool {
+        match self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find(|layer| layer.contains_key(name))
+        {
+            Some(layer) => layer.remove(name).is_some(),
+            None => {
+                self.distributions.remove(name);
+                self.uncertainties.remove(name);
+                self.r_calculator.variables.remove(name).is_some()
+                    || self.derived.remove(name).is_some()
+                    || self.complex_variables.remove(name).is_some()
+            }
+        }
+    }
+
+    /// Evaluate the real or imaginary part of a CalculatorComplex with complex arithmetic.
+    fn evaluate_complex_part(
+        &mut self,
+        py: Python,
+        part: CalculatorFloat,
+    ) -> PyResult<Complex<f64>> {
+        match part {
+            CalculatorFloat::Float(x) => Ok(Complex::new(x, 0.0)),
+            CalculatorFloat::Str(input) => self
+                .with_context(py, |context| {
+                    let parsed = expression::parse(&input)?;
+                    expression::check_functions(&parsed, context)?;
+                    expression::evaluate_complex(&parsed, context)
+                })
+                .map_err(|err| expression_error_to_py(py, err, &input)),
+        }
+    }
+
+    /// Evaluate an expression for all points of a parameter grid in the innermost scope.
+    ///
+    /// Returns the values in row-major order, the last parameter varying fastest.
+    fn evaluate_grid(
+        &mut self,
+        py: Python,
+        parsed: &Expression,
+        source: &str,
+        parameters: &[(String, Vec<f64>)],
+    ) -> Result<Vec<f64>, ExpressionError> {
+        let size: usize = parameters.iter().map(|(_, values)| values.len()).product();
+        let mut results = Vec::with_capacity(size);
+        if size == 0 {
+            return Ok(results);
+        }
+        let mut index = vec![0; parameters.len()];
+        for (name, values) in parameters.iter() {
+            if let Some(layer) = self.scopes.last_mut() {
+                layer.insert(name.clone(), values[0]);
+            }
+        }
+        loop {
+            results.push(self.evaluate_real(py, parsed, source)?);
+            // Advance the grid index like an odometer, updating only the changed parameters
+            let mut axis = parameters.len();
+            loop {
+                if axis == 0 {
+                    return Ok(results);
+                }
+                axis -= 1;
+                let (name, values) = &parameters[axis];
+                index[axis] = (index[axis] + 1) % values.len();
+                if let Some(layer) = self.scopes.last_mut() {
+                    layer.insert(name.clone(), values[index[axis]]);
+                }
+                if index[axis] != 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Evaluate definitions in dependency order and store the results as variables.
+    ///
+    /// The definitions are evaluated in a temporary scope, so the Calculator is
+    /// only changed when all definitions could be evaluated.
+    fn apply_definitions(
+        &mut self,
+        py: Python,
+        definitions: Vec<(String, CalculatorFloat)>,
+    ) -> PyResult<HashMap<String, f64>> {
+        let mut parsed: HashMap<String, Expression> = HashMap::new();
+        let mut sources: HashMap<String, String> = HashMap::new();
+        for (name, value) in definitions {
+            validate_variable_name(&name)?;
+            self.check_writable(&name)?;
+            let expression = match value {
+                CalculatorFloat::Float(x) => Expression {
+                    kind: ExpressionKind::Number(x),
+                    start: 0,
+                    end: 0,
+                },
+                CalculatorFloat::Str(source) => {
+                    let expression = expression::parse(&source).map_err(|err| {
+                        expression_error_to_py(py, err, &format!("{} = {}", name, source))
+                    })?;
+                    sources.insert(name.clone(), source);
+                    expression
+                }
+            };
+            parsed.insert(name, expression);
+        }
+        let order = expression::sort_definitions(&parsed)
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        self.scopes.push(HashMap::new());
+        let result = self.evaluate_sorted_definitions(py, &order, &parsed, &sources);
+        let values = self.scopes.pop().unwrap_or_default();
+        result?;
+        for (name, value) in values.iter() {
+            self.store_observed(py, name, VariableValue::Real(*value));
+        }
+        Ok(values)
+    }
+
+    /// Parse and validate derived variables without changing the Calculator.
+    ///
+    /// Fails when a name is invalid or read-only, an expression can not be parsed
+    /// or the new and existing derived variables depend on each other in a cycle.
+    fn derived_variables(
+        &self,
+        definitions: &[(String, String)],
+    ) -> PyResult<Vec<(String, DerivedVariable)>> {
+        let mut derived_variables = Vec::with_capacity(definitions.len());
+        for (name, source) in definitions {
+            validate_variable_name(name)?;
+            self.check_writable(name)?;
+            let parsed = expression::parse(source)
+                .map_err(|err| PyValueError::new_err(format!("{}; expression: {}", err, source)))?;
+            derived_variables.push((
+                name.clone(),
+                DerivedVariable {
+                    source: source.clone(),
+                    expression: parsed,
+                },
+            ));
+        }
+        if !derived_variables.is_empty() {
+            let mut expressions: HashMap<String, Expression> = self
+                .derived
+                .iter()
+                .map(|(name, derived)| (name.clone(), derived.expression.clone()))
+                .collect();
+            for (name, derived) in derived_variables.iter() {
+                expressions.insert(name.clone(), derived.expression.clone());
+            }
+            expression::sort_definitions(&expressions)
+                .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        }
+        Ok(derived_variables)
+    }
+
+    /// Store a derived variable, replacing a variable of the same name.
+    fn insert_derived(&mut self, name: String, derived: DerivedVariable) {
+        self.r_calculator.variables.remove(&name);
+        self.complex_variables.remove(&name);
+        self.distributions.remove(&name);
+        self.uncertainties.remove(&name);
+        self.derived.insert(name, derived);
+    }
+
+    /// Evaluate sorted definitions into the innermost scope.
+    fn evaluate_sorted_definitions(
+        &mut self,
+        py: Python,
+        order: &[String],
+        parsed: &HashMap<String, Expression>,
+        sources: &HashMap<String, String>,
+    ) -> PyResult<()> {
+        for name in order {
+            let definition = &parsed[name];
+            let source = sources.get(name).map(String::as_str).unwrap_or_default();
+            let value = self.evaluate_real(py, definition, source).map_err(|err| {
+                expression_error_to_py(py, err, &format!("{} = {}", name, source))
+            })?;
+            if let Some(layer) = self.scopes.last_mut() {
+                layer.insert(name.clone(), value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pair the old values of variables changed by a scope with their current values.
+    ///
+    /// Variables whose value did not change are left out.
+    fn scope_changes(
+        &self,
+        py: Python,
+        old: Vec<(String, Option<PyObject>)>,
+    ) -> Vec<VariableChange> {
+        old.into_iter()
+            .filter_map(|(name, old)| {
+                let new = self.visible_value(py, &name);
+                let unchanged = match (&old, &new) {
+                    (Some(old), Some(new)) => old
+                        .as_ref(py)
+                        .rich_compare(new, CompareOp::Eq)
+                        .and_then(|equal| equal.is_true())
+                        .unwrap_or(false),
+                    (None, None) => true,
+                    _ => false,
+                };
+                if unchanged {
+                    None
+                } else {
+                    Some((name, old, new))
+                }
+            })
+            .collect()
+    }
+
+    /// Save the variables of the Calculator for a scope with the given id.
+    fn scope_state(&self, id: usize) -> ScopeState {
+        ScopeState {
+            id,
+            variables: self.r_calculator.variables.clone(),
+            complex_variables: self.complex_variables.clone(),
+            derived: self.derived.clone(),
+            distributions: self.distributions.clone(),
+            uncertainties: self.uncertainties.clone(),
+            scopes: self.scopes.clone(),
+        }
+    }
+
+    /// Return the names of all variables of the Calculator and of a saved state.
+    fn state_names(&self, state: &ScopeState) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        for (variables, scopes) in [
+            (&self.r_calculator.variables, &self.scopes),
+            (&state.variables, &state.scopes),
+        ]
+        .iter()
+        {
+            names.extend(variables.keys().cloned());
+            names.extend(scopes.iter().flat_map(|layer| layer.keys().cloned()));
+        }
+        names.extend(self.complex_variables.keys().cloned());
+        names.extend(state.complex_variables.keys().cloned());
+        names.extend(self.derived.keys().cloned());
+        names.extend(state.derived.keys().cloned());
+        names
+    }
+
+    /// Restore the variables saved by `scope_state`.
+    fn restore_state(&mut self, state: ScopeState) {
+        self.r_calculator.variables = state.variables;
+        self.complex_variables = state.complex_variables;
+        self.derived = state.derived;
+        self.distributions = state.distributions;
+        self.uncertainties = state.uncertainties;
+        self.scopes = state.scopes;
+    }
+
+    /// Return a copy of the Calculator with all active scopes merged into its variables.
+    fn flattened(&self) -> CalculatorWrapper {
+        let mut r_calculator = self.r_calculator.clone();
+        r_calculator.variables = self.variables();
+        CalculatorWrapper {
+            r_calculator,
+            functions: self.functions.clone(),
+            scopes: Vec::new(),
+            scope_states: Vec::new(),
+            next_scope: 0,
+            derived: self.derived.clone(),
+            resolver: self.resolver.clone(),
+            cache_resolved: self.cache_resolved,
+            constants: self.constants.clone(),
+            complex_variables: self.complex_variables.clone(),
+            observers: Vec::new(),
+            next_observer: 0,
+            changes: Vec::new(),
+            precision: self.precision,
+            distributions: self.distributions.clone(),
+            uncertainties: self.uncertainties.clone(),
+            expression_cache: self.expression_cache.clone(),
+        }
+    }
+
+    /// Return the values of all plain, complex and derived variables sorted by name.
+    fn sorted_items(&self) -> PyResult<Vec<(String, PyObject)>> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        self.keys()
+            .into_iter()
+            .map(|name| match self.lookup_object(py, &name) {
+                Some(value) => Ok((name, value?)),
+                None => Err(PyKeyError::new_err(name)),
+            })
+            .collect()
+    }
+}
+
+/// Evaluation context giving the expression evaluator access to a Calculator.
+struct CalculatorContext<'a> {
+    py: Python<'a>,
+    calculator: &'a CalculatorWrapper,
+    resolved: RefCell<HashMap<String, f64>>,
+}
+
+impl<'a> CalculatorContext<'a> {
+    /// Create a new evaluation context for a Calculator.
+    fn new(py: Python<'a>, calculator: &'a CalculatorWrapper) -> Self {
+        CalculatorContext {
+            py,
+            calculator,
+            resolved: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Return the value of a plain or derived variable without calling the resolver.
+    fn known_variable(&self, name: &str) -> Option<Result<f64, ExpressionError>> {
+        if let Some(value) = self.calculator.constants.get(name) {
+            return Some(Ok(*value));
+        }
+        if let Some(value) = self.calculator.variable(name) {
+            return Some(Ok(value));
+        }
+        if self.calculator.complex_value(name).is_some() {
+            return Some(Err(ExpressionError::ComplexVariable {
+                name: name.to_string(),
+            }));
+        }
+        let derived = self.calculator.derived.get(name)?;
+        Some(
+            expression::evaluate(&derived.expression, self).map_err(|err| {
+                ExpressionError::DefinitionFailed {
+                    name: name.to_string(),
+                    err: Box::new(err),
+                }
+            }),
+        )
+    }
+
+    /// Return the complex value of a variable without calling the resolver.
+    fn known_complex_variable(&self, name: &str) -> Option<Result<Complex<f64>, ExpressionError>> {
+        if !self.calculator.is_complex(name) {
+            return self
+                .known_variable(name)
+                .map(|value| value.map(|value| Complex::new(value, 0.0)));
+        }
+        if let Some(value) = self.calculator.complex_value(name) {
+            return Some(Ok(value));
+        }
+        let derived = self.calculator.derived.get(name)?;
+        Some(
+            expression::evaluate_complex(&derived.expression, self).map_err(|err| {
+                ExpressionError::DefinitionFailed {
+                    name: name.to_string(),
+                    err: Box::new(err),
+                }
+            }),
+        )
+    }
+
+    /// Ask the resolver of the Calculator for the value of an unknown variable.
+    ///
+    /// Returns None when there is no resolver or the resolver returns None.
+    fn resolve(&self, name: &str) -> Option<Result<f64, ExpressionError>> {
+        if let Some(value) = self.resolved.borrow().get(name) {
+            return Some(Ok(*value));
+        }
+        let resolver = self.calculator.resolver.as_ref()?;
+        let result = resolver.call1(self.py, (name,)).and_then(|value| {
+            let value = value.as_ref(self.py);
+            if value.is_none() {
+                Ok(None)
+            } else {
+                value.extract::<f64>().map(Some)
+            }
+        });
+        match result {
+            Ok(Some(value)) => {
+                self.resolved.borrow_mut().insert(name.to_string(), value);
+                Some(Ok(value))
+            }
+            Ok(None) => None,
+            Err(err) => Some(Err(ExpressionError::ResolverFailed {
+                name: name.to_string(),
+                err,
+            })),
+        }
+    }
+}
+
+impl EvaluationContext for CalculatorContext<'_> {
+    fn variable(&self, name: &str) -> Option<Result<f64, ExpressionError>> {
+        self.known_variable(name)
+    }
+
+    fn complex_variable(&self, name: &str) -> Option<Result<Complex<f64>, ExpressionError>> {
+        self.known_complex_variable(name)
+    }
+
+    fn resolve_variable(&self, name: &str) -> Option<Result<f64, ExpressionError>> {
+        self.resolve(name)
+    }
+
+    fn function_arity(&self, name: &str) -> Option<usize> {
+        self.calculator.functions.get(name).map(|f| f.arity)
+    }
+
+    fn call_function(&self, name: &str, arguments: &[f64]) -> Option<Result<f64, ExpressionError>> {
+        let registered = self.calculator.functions.get(name)?;
+        let result = registered
+            .function
+            .call1(self.py, PyTuple::new(self.py, arguments.to_vec()))
+            .and_then(|value| value.extract::<f64>(self.py));
+        Some(result.map_err(|err| ExpressionError::FunctionFailed {
+            name: name.to_string(),
+            err,
+        }))
+    }
+
+    fn call_function_complex(
+        &self,
+        name: &str,
+        arguments: &[Complex<f64>],
+    ) -> Option<Result<Complex<f64>, ExpressionError>> {
+        let registered = self.calculator.functions.get(name)?;
+        let py_arguments = if arguments.iter().all(|argument| argument.im == 0.0) {
+            PyTuple::new(self.py, arguments.iter().map(|argument| argument.re))
+        } else {
+            PyTuple::new(self.py, arguments.to_vec())
+        };
+        let result = registered
+            .function
+            .call1(self.py, py_arguments)
+            .and_then(|value| value.extract::<Complex<f64>>(self.py));
+        Some(result.map_err(|err| ExpressionError::FunctionFailed {
+            name: name.to_string(),
+            err,
+        }))
+    }
+}
+
+impl PrecisionContext for CalculatorContext<'_> {
+    fn precise_variable(
+        &self,
+        name: &str,
+        digits: u64,
+    ) -> Option<Result<Decimal, ExpressionError>> {
+        match self.calculator.visible_derived(name) {
+            Some(derived) => Some(
+                precision::evaluate(&derived.expression, &derived.source, self, digits as usize)
+                    .map_err(|err| ExpressionError::DefinitionFailed {
+                        name: name.to_string(),
+                        err: Box::new(err),
+                    }),
+            ),
+            None => self
+                .variable(name)
+                .map(|value| value.and_then(|value| precision::decimal_from_f64(value, name))),
+        }
+    }
+}
+
+impl GradientContext for CalculatorContext<'_> {
+    fn derived_expression(&self, name: &str) -> Option<&Expression> {
+        self.calculator
+            .visible_derived(name)
+            .map(|derived| &derived.expression)
+    }
+}
+
+/// Wrap the error of a statement of a multi-statement script with the failing statement.
+fn statement_error(
+    err: ExpressionError,
+    input: &str,
+    index: usize,
+    statement: &Statement,
+) -> ExpressionError {
+    ExpressionError::StatementFailed {
+        index: index + 1,
+        statement: input[statement.start..statement.end].to_string(),
+        err: Box::new(err),
+    }
+}
+
+/// Convert an expression error into the corresponding Python error.
+///
+/// Exceptions raised by registered Python functions keep their type
+/// and are chained as the cause of the returned error.
+/// Syntax errors carry the position of the error in the `position` attribute.
+///
+/// # Arguments
+///
+/// * `py` - Python GIL token
+/// * `err` - the error to be converted
+/// * `input` - the expression in which the error occurred
+///
+pub(crate) fn expression_error_to_py(py: Python, err: ExpressionError, input: &str) -> PyErr {
+    match err.python_error() {
+        Some(original) => {
+            let message = format!("{}: {}; expression: {}", err, original.instance(py), input);
+            let converted = PyErr::from_type(original.ptype(py), message);
+            let _ = converted
+                .instance(py)
+                .setattr("__cause__", original.instance(py));
+            converted
+        }
+        None => {
+            let converted = PyValueError::new_err(format!("{}; expression: {}", err, input));
+            if let ExpressionError::ParsingError { position, .. } = &err {
+                let _ = converted.instance(py).setattr("position", *position);
+            }
+            converted
+        }
+    }
+}
+
+#[pyproto]
+impl PyObjectProtocol for CalculatorWrapper {
+    /// Return the __richcmp__ magic method to perform rich comparison
+    /// operations on Calculator.
+    ///
+    /// Two Calculators are equal when they contain the same variables with the same values.
+    ///
+    /// # Arguments
+    ///
+    /// * `&self` - the CalculatorWrapper object
+    /// * `other` - the object to compare self to
+    /// * `op` - equal or not equal
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<bool>` - whether the two operations compared evaluated to True or False
+    ///
+    fn __richcmp__(&self, other: Py<PyAny>, op: CompareOp) -> PyResult<bool> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        let equal = match other.extract::<PyRef<CalculatorWrapper>>(py) {
+            Ok(other_calculator) => {
+                self.variables() == other_calculator.variables()
+                    && self.complex_variables == other_calculator.complex_variables
+                    && self.definitions() == other_calculator.definitions()
+                    && self.constants == other_calculator.constants
+                    && self.distributions == other_calculator.distributions
+                    && self.uncertainties == other_calculator.uncertainties
+            }
+            Err(_) => false,
+        };
+        match op {
+            CompareOp::Eq => Ok(equal),
+            CompareOp::Ne => Ok(!equal),
+            _ => Err(PyNotImplementedError::new_err(
+                "Other comparison not implemented.",
+            )),
+        }
+    }
+}
+
+#[pyproto]
+impl PyMappingProtocol for CalculatorWrapper {
+    /// Return the number of variables set in the Calculator.
+    fn __len__(&self) -> usize {
+        self.keys().len()
+    }
+
+    /// Return the float or complex value of a variable, raising KeyError when it is not set.
+    fn __getitem__(&self, key: String) -> PyResult<PyObject> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        match self.lookup_object(py, &key) {
+            Some(value) => value,
+            None => Err(PyKeyError::new_err(key)),
+        }
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for CalculatorWrapper {
+    /// Return True when the variable or a loaded constant of this name is set in the Calculator.
+    fn __contains__(&self, key: String) -> bool {
+        self.variable(&key).is_some()
+            || self.derived.contains_key(&key)
+            || self.complex_variables.contains_key(&key)
+            || self.constants.contains_key(&key)
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for CalculatorWrapper {
+    /// Return an iterator over the sorted variable names of the Calculator.
+    fn __iter__(slf: PyRef<Self>) -> PyResult<PyObject> {
+        let py = slf.py();
+        let keys = slf.keys().to_object(py);
+        let iterator = PyIterator::from_object(py, &keys)?;
+        Ok(iterator.to_object(py))
+    }
+}
+
+/// Check that a string can be used as a variable name in an expression.
+///
+/// The reserved words `and`, `or`, `not` and `if` are rejected. They could be used as
+/// variable names before the logical operators were added to the expression grammar.
+///
+/// # Arguments
+///
+/// * `name` - the variable name to be checked
+///
+/// # Returns
+///
+/// `PyResult<()>` - Ok when the name is valid, PyValueError otherwise
+///
+pub fn validate_variable_name(name: &str) -> PyResult<()> {
+    if expression::is_keyword(name) {
+        return Err(PyValueError::new_err(format!(
+            "Invalid variable name: {:?} is a reserved word of the expression grammar \
+             (and, or, not, if)",
+            name
+        )));
+    }
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(first) => {
+            (first.is_ascii_alphabetic() || first == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(PyValueError::new_err(format!(
+            "Invalid variable name: {:?}",
+            name
+        )))
+    }
+}
+
+/// Extract an entry of a pickled Calculator state, returning the default when it is missing.
+///
+/// # Arguments
+///
+/// * `state` - dictionary created by Calculator.__getstate__
+/// * `key` - name of the entry
+///
+/// # Returns
+///
+/// `PyResult<T>` - value of the entry or PyTypeError when it has the wrong type
+///
+fn state_item<'a, T: FromPyObject<'a> + Default>(state: &'a PyDict, key: &str) -> PyResult<T> {
+    match state.get_item(key) {
+        Some(value) => value.extract(),
+        None => Ok(T::default()),
+    }
+}
+
+/// Convert a string or path-like Python object to a path using `os.fspath`.
+///
+/// # Arguments
+///
+/// * `path` - str, pathlib.Path or other object implementing `__fspath__`
+///
+/// # Returns
+///
+/// `PyResult<PathBuf>` - the path or PyTypeError when the object is not path-like
+///
+fn extract_path(path: &PyAny) -> PyResult<PathBuf> {
+    let gil = pyo3::Python::acquire_gil();
+    let py = gil.python();
+    let fspath = py.import("os")?.call1("fspath", (path,))?;
+    let path: String = fspath
+        .extract()
+        .map_err(|_| PyTypeError::new_err("Path must be a str or str-based path-like object"))?;
+    Ok(PathBuf::from(path))
+}
+
+/// Collect and validate float variables from a mapping or iterable and keyword arguments.
+///
+/// # Arguments
+///
+/// * `variables` - Optional mapping or iterable of (name, value) pairs
+/// * `kwargs` - Optional dictionary of keyword arguments
+///
+/// # Returns
+///
+/// `PyResult<Vec<(String, f64)>>` - validated variable names and values in input order
+///
+pub(crate) fn extract_variables(
+    variables: Option<&PyAny>,
+    kwargs: Option<&PyDict>,
+) -> PyResult<Vec<(String, f64)>> {
+    extract_pairs(variables, kwargs)?
+        .into_iter()
+        .map(|(name, val)| {
+            let val: f64 = val.extract().map_err(|_| {
+                PyTypeError::new_err(format!(
+                    "Value of variable {} can not be converted to float",
+                    name
+                ))
+            })?;
+            Ok((name, val))
+        })
+        .collect()
+}
+
+/// Collect and validate float or complex variables from a mapping or iterable
+/// and keyword arguments.
+///
+/// # Arguments
+///
+/// * `variables` - Optional mapping or iterable of (name, value) pairs
+/// * `kwargs` - Optional dictionary of keyword arguments
+///
+/// # Returns
+///
+/// `PyResult<Vec<(String, VariableValue)>>` - validated variable names and values in input order
+///
+fn extract_values(
+    variables: Option<&PyAny>,
+    kwargs: Option<&PyDict>,
+) -> PyResult<Vec<(String, VariableValue)>> {
+    extract_pairs(variables, kwargs)?
+        .into_iter()
+        .map(|(name, val)| {
+            let val = VariableValue::extract(&name, val)?;
+            Ok((name, val))
+        })
+        .collect()
+}
+
+/// Collect (name, value) pairs from a mapping or iterable and keyword arguments
+/// and validate the names.
+fn extract_pairs<'a>(
+    variables: Option<&'a PyAny>,
+    kwargs: Option<&'a PyDict>,
+) -> PyResult<Vec<(String, &'a PyAny)>> {
+    let mut pairs: Vec<(&PyAny, &PyAny)> = Vec::new();
+    if let Some(input) = variables {
+        let iterable = if input.hasattr("items")? {
+            input.call_method0("items")?
+        } else {
+            input
+        };
+        for item in iterable.iter()? {
+            let (name, val): (&PyAny, &PyAny) = item?.extract().map_err(|_| {
+                PyTypeError::new_err("Variables must be given as (name, value) pairs")
+            })?;
+            pairs.push((name, val));
+        }
+    }
+    if let Some(dict) = kwargs {
+        pairs.extend(dict.iter());
+    }
+    let mut extracted: Vec<(String, &PyAny)> = Vec::with_capacity(pairs.len());
+    for (name, val) in pairs {
+        let name: String = name
+            .extract()
+            .map_err(|_| PyTypeError::new_err("Variable names must be strings"))?;
+        validate_variable_name(&name)?;
+        extracted.push((name, val));
+    }
+    Ok(extracted)
+}
+
+/// Extract a correlation matrix and return its Cholesky factor.
+///
+/// # Arguments
+///
+/// * `correlation` - dict mapping pairs of variable names to correlation coefficients
+///   or a full matrix in the order of `variables`
+/// * `variables` - names of the variables with uncertainty sorted by name
+///
+/// # Returns
+///
+/// `PyResult<Vec<Vec<f64>>>` - factor of the matrix, PyValueError when the matrix is not
+/// symmetric with unit diagonal, entries in [-1, 1] and positive semi-definite
+///
+fn correlation_factor(correlation: &PyAny, variables: &[String]) -> PyResult<Vec<Vec<f64>>> {
+    let size = variables.len();
+    let matrix: Vec<Vec<f64>> = match correlation.downcast::<PyDict>() {
+        Ok(pairs) => {
+            let mut matrix: Vec<Vec<f64>> = (0..size)
+                .map(|i| (0..size).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+                .collect();
+            for (key, value) in pairs.iter() {
+                let (lhs, rhs): (String, String) = key.extract()?;
+                let value: f64 = value.extract()?;
+                let position = |name: &str| {
+                    variables
+                        .iter()
+                        .position(|variable| variable == name)
+                        .ok_or_else(|| {
+                            PyValueError::new_err(format!(
+                                "Correlated variable {} has no uncertainty",
+                                name
+                            ))
+                        })
+                };
+                let (i, j) = (position(&lhs)?, position(&rhs)?);
+                if i == j || !(-1.0..=1.0).contains(&value) {
+                    return Err(PyValueError::new_err(format!(
+                        "Invalid correlation of {} and {}: {}; coefficients must be in [-1, 1] \
+                         between different variables",
+                        lhs, rhs, value
+                    )));
+                }
+                matrix[i][j] = value;
+                matrix[j][i] = value;
+            }
+            matrix
+        }
+        Err(_) => {
+            let matrix: Vec<Vec<f64>> = correlation.extract()?;
+            if matrix.len() != size || matrix.iter().any(|row| row.len() != size) {
+                return Err(PyValueError::new_err(format!(
+                    "Correlation matrix must have shape ({}, {}) with rows and columns in the \
+                     order of uncertainties(): {:?}",
+                    size, size, variables
+                )));
+            }
+            for (i, row) in matrix.iter().enumerate() {
+                for (j, value) in row.iter().enumerate() {
+                    let valid = if i == j {
+                        *value == 1.0
+                    } else {
+                        (-1.0..=1.0).contains(value) && *value == matrix[j][i]
+                    };
+                    if !valid {
+                        return Err(PyValueError::new_err(format!(
+                            "Invalid correlation matrix entry ({}, {}): {}; the matrix must be \
+                             symmetric with unit diagonal and entries in [-1, 1]",
+                            i, j, value
+                        )));
+                    }
+                }
+            }
+            matrix
+        }
+    };
+    uncertainty::cholesky(&matrix)
+        .ok_or_else(|| PyValueError::new_err("Correlation matrix must be positive semi-definite"))
+}
+
+/// Context manager returned by Calculator.scope binding temporary variables.
+#[pyclass(name = "CalculatorScope", module = "qoqo_calculator_pyo3")]
+pub struct CalculatorScopeWrapper {
+    calculator: Py<CalculatorWrapper>,
+    bindings: HashMap<String, f64>,
+    /// Id of the scope while it is active.
+    active: Option<usize>,
+}
+
+#[pyproto]
+impl PyContextProtocol for CalculatorScopeWrapper {
+    /// Activate the temporary variables and return the Calculator.
+    fn __enter__(&mut self) -> PyResult<Py<CalculatorWrapper>> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        if self.active.is_some() {
+            return Err(PyValueError::new_err("Scope is already active"));
+        }
+        let (changes, observers) = {
+            let mut calculator = self.calculator.try_borrow_mut(py)?;
+            let old: Vec<(String, Option<PyObject>)> = self
+                .bindings
+                .keys()
+                .map(|name| (name.clone(), calculator.visible_value(py, name)))
+                .collect();
+            let id = calculator.next_scope;
+            calculator.next_scope += 1;
+            self.active = Some(id);
+            let state = calculator.scope_state(id);
+            calculator.scope_states.push(state);
+            calculator.scopes.push(self.bindings.clone());
+            (
+                calculator.scope_changes(py, old),
+                calculator.observers.clone(),
+            )
+        };
+        notify_observers(py, &observers, &changes)?;
+        Ok(self.calculator.clone())
+    }
+
+    /// Remove the temporary variables and restore the variables changed inside the scope.
+    ///
+    /// Raises a RuntimeError when a scope entered after this one is still active.
+    fn __exit__(
+        &mut self,
+        _ty: Option<&'p PyType>,
+        _value: Option<&'p PyAny>,
+        _traceback: Option<&'p PyAny>,
+    ) -> PyResult<bool> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        if let Some(id) = self.active {
+            let (changes, observers) = {
+                let mut calculator = self.calculator.try_borrow_mut(py)?;
+                let state = match calculator.scope_states.last() {
+                    Some(state) if state.id == id => calculator.scope_states.pop(),
+                    _ => None,
+                };
+                let state = state.ok_or_else(|| {
+                    PyRuntimeError::new_err(
+                        "Scopes must be exited in the reverse order in which they were entered",
+                    )
+                })?;
+                self.active = None;
+                let old: Vec<(String, Option<PyObject>)> = calculator
+                    .state_names(&state)
+                    .into_iter()
+                    .map(|name| {
+                        let value = calculator.visible_value(py, &name);
+                        (name, value)
+                    })
+                    .collect();
+                calculator.restore_state(state);
+                (
+                    calculator.scope_changes(py, old),
+                    calculator.observers.clone(),
+                )
+            };
+            notify_observers(py, &observers, &changes)?;
+        }
+        Ok(false)
+    }
+}
+
+/// Call all observers of a Calculator for each variable change.
+fn notify_observers(
+    py: Python,
+    observers: &[(usize, PyObject)],
+    changes: &[VariableChange],
+) -> PyResult<()> {
+    for (name, old, new) in changes {
+        for (_, callback) in observers {
+            callback.call1(py, (name.as_str(), old.clone(), new.clone()))?;
+        }
+    }
+    Ok(())
+}
+
+///  Parse a string expression.
+///
+/// Uses the expression parser of the Calculator class, like `parse_str_partial`
+/// and `validate_str`.
+///
+/// # Arguments
+///
+/// * `expression` - Expression that is parsed
+///
+pub fn parse_str(expression: &str) -> PyResult<f64> {
+    parse_str_with_variables(expression, None, None)
+}
+
+///  Parse a string expression with the given variables.
+///
+/// Uses the expression parser of the Calculator class, like `parse_str_partial`
+/// and `validate_str`.
+///
+/// # Arguments
+///
+/// * `expression` - Expression that is parsed
+/// * `variables` - Optional mapping or iterable of (name, value) pairs
+/// * `kwargs` - Variables given as keyword arguments
+///
+/// # Returns
+///
+/// `PyResult<f64>` - value of the expression, ValueError when a variable is not set
+///
+pub fn parse_str_with_variables(
+    expression: &str,
+    variables: Option<&PyAny>,
+    kwargs: Option<&PyDict>,
+) -> PyResult<f64> {
+    let gil = pyo3::Python::acquire_gil();
+    let py = gil.python();
+    CalculatorWrapper::new(variables, kwargs)?.evaluate_str(py, expression)
+}
+
+///  Partially evaluate a string expression with the given variables.
+///
+/// Uses the expression parser of the Calculator class, qoqo_calculator can not
+/// evaluate expressions partially.
+///
+/// # Arguments
+///
+/// * `expression` - Expression that is parsed
+/// * `variables` - Optional mapping or iterable of (name, value) pairs
+/// * `kwargs` - Variables given as keyword arguments
+///
+/// # Returns
+///
+/// `PyResult<CalculatorFloat>` - Float when all variables are set, otherwise
+///                               the expression with the set variables substituted
+///
+pub fn parse_str_partial(
+    expression: &str,
+    variables: Option<&PyAny>,
+    kwargs: Option<&PyDict>,
+) -> PyResult<CalculatorFloat> {
+    let gil = pyo3::Python::acquire_gil();
+    let py = gil.python();
+    CalculatorWrapper::new(variables, kwargs)?.simplify_str(py, expression)
+}
+
+///  Check a string expression without evaluating it.
+///
+/// # Arguments
+///
+/// * `expression` - Expression that is checked
+///
+/// # Returns
+///
+/// `PyResult<PyObject>` - report of the referenced names, see `CalculatorWrapper::validate`
+///
+pub fn validate_str(expression: &str) -> PyResult<PyObject> {
+    CalculatorWrapper::new(None, None)?.validate(expression)
 }
@@ -0,0 +1,90 @@
+// Copyright © 2020-2021 HQS Quantum Simulations GmbH. All Rights Reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+// in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the
+// License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+// express or implied. See the License for the specific language governing permissions and
+// limitations underthe License.
+
+//! constants module
+//!
+//! Sets of physical constants that can be loaded into a Calculator as read-only variables.
+
+/// CODATA 2018 values of physical constants in SI units.
+///
+/// Loading this set makes `e` the elementary charge instead of Euler's number.
+const CODATA2018: &[(&str, f64)] = &[
+    ("c", 299_792_458.0),
+    ("h", 6.626_070_15e-34),
+    ("hbar", 1.054_571_817e-34),
+    ("e", 1.602_176_634e-19),
+    ("k_B", 1.380_649e-23),
+    ("N_A", 6.022_140_76e23),
+    ("m_e", 9.109_383_701_5e-31),
+    ("m_p", 1.672_621_923_69e-27),
+    ("epsilon_0", 8.854_187_812_8e-12),
+    ("mu_0", 1.256_637_062_12e-6),
+    ("mu_B", 9.274_010_078_3e-24),
+    ("alpha", 7.297_352_569_3e-3),
+];
+
+/// Natural units with hbar = c = k_B = 1, masses in eV and the
+/// elementary charge in Lorentz-Heaviside units.
+const NATURAL: &[(&str, f64)] = &[
+    ("c", 1.0),
+    ("hbar", 1.0),
+    ("k_B", 1.0),
+    ("e", 0.302_822_120_87),
+    ("m_e", 510_998.95),
+    ("m_p", 938_272_088.16),
+    ("alpha", 7.297_352_569_3e-3),
+];
+
+/// Conversion factors for frequencies and times to SI units.
+const UNITS: &[(&str, f64)] = &[
+    ("Hz", 1.0),
+    ("kHz", 1e3),
+    ("MHz", 1e6),
+    ("GHz", 1e9),
+    ("ms", 1e-3),
+    ("us", 1e-6),
+    ("ns", 1e-9),
+    ("ps", 1e-12),
+];
+
+/// Names of all available constant sets.
+const CONSTANT_SETS: &[&str] = &["codata2018", "natural", "units"];
+
+/// Return the names of all available constant sets.
+///
+/// # Returns
+///
+/// `Vec<&'static str>` - names of the constant sets
+///
+pub fn constant_set_names() -> Vec<&'static str> {
+    CONSTANT_SETS.to_vec()
+}
+
+/// Return the constants of a constant set.
+///
+/// # Arguments
+///
+/// * `name` - name of the constant set
+///
+/// # Returns
+///
+/// `Option<&'static [(&'static str, f64)]>` - names and values of the constants
+///                                            or None when there is no such set
+///
+pub fn constant_set(name: &str) -> Option<&'static [(&'static str, f64)]> {
+    match name {
+        "codata2018" => Some(CODATA2018),
+        "natural" => Some(NATURAL),
+        "units" => Some(UNITS),
+        _ => None,
+    }
+}
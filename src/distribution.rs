@@ -0,0 +1,307 @@
+// Copyright © 2020-2021 HQS Quantum Simulations GmbH. All Rights Reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+// in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the
+// License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+// express or implied. See the License for the specific language governing permissions and
+// limitations underthe License.
+
+//! distribution module
+//!
+//! Probability distributions of random variables used for Monte Carlo sampling.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use rand::Rng;
+use rand_distr::StandardNormal;
+use std::collections::HashMap;
+
+/// Names of the supported distributions and their parameters.
+const DISTRIBUTIONS: [(&str, &[&str]); 3] = [
+    ("normal", &["mean", "std"]),
+    ("uniform", &["low", "high"]),
+    ("truncated_normal", &["mean", "std", "low", "high"]),
+];
+
+/// Probability distribution of a random variable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    /// Normal distribution with mean and standard deviation.
+    Normal { mean: f64, std: f64 },
+    /// Uniform distribution on the interval [low, high).
+    Uniform { low: f64, high: f64 },
+    /// Normal distribution restricted to the interval [low, high].
+    TruncatedNormal {
+        mean: f64,
+        std: f64,
+        low: f64,
+        high: f64,
+    },
+}
+
+impl Distribution {
+    /// Create a distribution from its name and parameters.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - name of the distribution: `normal`, `uniform` or `truncated_normal`
+    /// * `parameters` - values of all parameters of the distribution by name
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<Distribution>` - the distribution or a ValueError for invalid parameters
+    ///
+    pub fn from_parameters(kind: &str, parameters: &HashMap<String, f64>) -> PyResult<Self> {
+        let names = DISTRIBUTIONS
+            .iter()
+            .find(|(name, _)| *name == kind)
+            .map(|(_, names)| *names)
+            .ok_or_else(|| {
+                PyValueError::new_err(format!(
+                    "Unknown distribution {}; available distributions: {}",
+                    kind,
+                    distribution_names().join(", ")
+                ))
+            })?;
+        if let Some(unknown) = parameters
+            .keys()
+            .find(|name| !names.contains(&name.as_str()))
+        {
+            return Err(PyValueError::new_err(format!(
+                "Unknown parameter {} of {} distribution",
+                unknown, kind
+            )));
+        }
+        let parameter = |name: &str| {
+            parameters.get(name).copied().ok_or_else(|| {
+                PyValueError::new_err(format!(
+                    "Missing parameter {} of {} distribution",
+                    name, kind
+                ))
+            })
+        };
+        let distribution = match kind {
+            "normal" => Distribution::Normal {
+                mean: parameter("mean")?,
+                std: parameter("std")?,
+            },
+            "uniform" => Distribution::Uniform {
+                low: parameter("low")?,
+                high: parameter("high")?,
+            },
+            _ => Distribution::TruncatedNormal {
+                mean: parameter("mean")?,
+                std: parameter("std")?,
+                low: parameter("low")?,
+                high: parameter("high")?,
+            },
+        };
+        distribution.validate()?;
+        Ok(distribution)
+    }
+
+    /// Return an error when the parameters do not describe a valid distribution.
+    fn validate(&self) -> PyResult<()> {
+        let valid = match *self {
+            Distribution::Normal { mean, std } => mean.is_finite() && std.is_finite() && std > 0.0,
+            Distribution::Uniform { low, high } => {
+                low.is_finite() && high.is_finite() && low < high && (high - low).is_finite()
+            }
+            Distribution::TruncatedNormal {
+                mean,
+                std,
+                low,
+                high,
+            } => mean.is_finite() && std.is_finite() && std > 0.0 && low < high,
+        };
+        if valid {
+            Ok(())
+        } else {
+            let parameters: Vec<String> = self
+                .parameters()
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            Err(PyValueError::new_err(format!(
+                "Invalid parameters of {} distribution: {}",
+                self.kind(),
+                parameters.join(", ")
+            )))
+        }
+    }
+
+    /// Return the name of the distribution.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Distribution::Normal { .. } => "normal",
+            Distribution::Uniform { .. } => "uniform",
+            Distribution::TruncatedNormal { .. } => "truncated_normal",
+        }
+    }
+
+    /// Return the names and values of the parameters of the distribution.
+    pub fn parameters(&self) -> Vec<(&'static str, f64)> {
+        let values = match *self {
+            Distribution::Normal { mean, std } => vec![mean, std],
+            Distribution::Uniform { low, high } => vec![low, high],
+            Distribution::TruncatedNormal {
+                mean,
+                std,
+                low,
+                high,
+            } => vec![mean, std, low, high],
+        };
+        DISTRIBUTIONS
+            .iter()
+            .find(|(name, _)| *name == self.kind())
+            .map(|(_, names)| names.iter().copied().zip(values).collect())
+            .unwrap_or_default()
+    }
+
+    /// Return the value the random variable has outside of sampling.
+    ///
+    /// This is the mean of a normal distribution, the mean clamped to the interval
+    /// of a truncated normal distribution and the center of a uniform distribution.
+    pub fn nominal_value(&self) -> f64 {
+        match *self {
+            Distribution::Normal { mean, .. } => mean,
+            Distribution::Uniform { low, high } => low + (high - low) / 2.0,
+            Distribution::TruncatedNormal {
+                mean, low, high, ..
+            } => mean.max(low).min(high),
+        }
+    }
+
+    /// Draw a random value from the distribution.
+    ///
+    /// A truncated normal distribution whose interval lies so far in the tail that it
+    /// can not be resolved in float precision returns its nominal value.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> PyResult<f64> {
+        match *self {
+            Distribution::Normal { mean, std } => {
+                Ok(mean + std * rng.sample::<f64, _>(StandardNormal))
+            }
+            Distribution::Uniform { low, high } => Ok(rng.gen_range(low..high)),
+            Distribution::TruncatedNormal {
+                mean,
+                std,
+                low,
+                high,
+            } => {
+                let (a, b) = ((low - mean) / std, (high - mean) / std);
+                if !(a < b && a < f64::INFINITY && b > f64::NEG_INFINITY) {
+                    return Ok(self.nominal_value());
+                }
+                let value = mean
+                    + std
+                        * truncated_standard_normal(a, b, rng).ok_or_else(|| {
+                            PyValueError::new_err(
+                                "Sampling the truncated normal distribution did not converge",
+                            )
+                        })?;
+                // Guard against rounding in the back transformation
+                Ok(value.max(low).min(high))
+            }
+        }
+    }
+}
+
+/// Return the names of the supported distributions.
+pub fn distribution_names() -> Vec<&'static str> {
+    DISTRIBUTIONS.iter().map(|(name, _)| *name).collect()
+}
+
+/// Maximal number of rejected proposals before sampling a truncated normal distribution fails.
+const MAX_REJECTIONS: usize = 10_000;
+
+/// Draw from the standard normal distribution truncated to [a, b].
+///
+/// Uses rejection sampling with the proposal of Robert (1995) that fits the interval,
+/// so the acceptance rate stays high even far in the tails. Returns None when no
+/// proposal is accepted within `MAX_REJECTIONS` tries.
+fn truncated_standard_normal<R: Rng + ?Sized>(a: f64, b: f64, rng: &mut R) -> Option<f64> {
+    if a >= 0.0 {
+        one_sided_truncated_normal(a, b, rng)
+    } else if b <= 0.0 {
+        one_sided_truncated_normal(-b, -a, rng).map(|z| -z)
+    } else if b - a >= 1.0 {
+        for _ in 0..MAX_REJECTIONS {
+            let z: f64 = rng.sample(StandardNormal);
+            if a <= z && z <= b {
+                return Some(z);
+            }
+        }
+        None
+    } else {
+        for _ in 0..MAX_REJECTIONS {
+            let z = rng.gen_range(a..b);
+            if rng.gen::<f64>() <= (-z * z / 2.0).exp() {
+                return Some(z);
+            }
+        }
+        None
+    }
+}
+
+/// Draw from the standard normal distribution truncated to [a, b] with 0 <= a < b.
+///
+/// The terms are arranged so that no intermediate result overflows for large a.
+fn one_sided_truncated_normal<R: Rng + ?Sized>(a: f64, b: f64, rng: &mut R) -> Option<f64> {
+    let rate = a / 2.0 + a.hypot(2.0) / 2.0;
+    if b - a <= 1.0 / rate {
+        for _ in 0..MAX_REJECTIONS {
+            let z = rng.gen_range(a..b);
+            if rng.gen::<f64>() <= ((a - z) * (a / 2.0 + z / 2.0)).exp() {
+                return Some(z);
+            }
+        }
+    } else {
+        for _ in 0..MAX_REJECTIONS {
+            // Exponential proposal shifted to start at a
+            let z = a - (1.0 - rng.gen::<f64>()).ln() / rate;
+            if z <= b && rng.gen::<f64>() <= (-(z - rate) * (z - rate) / 2.0).exp() {
+                return Some(z);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn test_truncated_normal_far_in_the_tail() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let narrow = Distribution::TruncatedNormal {
+            mean: 0.0,
+            std: 1e-200,
+            low: 1.0,
+            high: 2.0,
+        };
+        assert_eq!(narrow.sample(&mut rng).unwrap(), 1.0);
+        let distant = Distribution::TruncatedNormal {
+            mean: 0.0,
+            std: 1.0,
+            low: 1e300,
+            high: 1e301,
+        };
+        let value = distant.sample(&mut rng).unwrap();
+        assert!((1e300..=1e301).contains(&value));
+        let mirrored = Distribution::TruncatedNormal {
+            mean: 0.0,
+            std: 1.0,
+            low: -1e301,
+            high: -1e300,
+        };
+        let value = mirrored.sample(&mut rng).unwrap();
+        assert!((-1e301..=-1e300).contains(&value));
+    }
+}
@@ -0,0 +1,2063 @@
+// Copyright © 2020-2021 HQS Quantum Simulations GmbH. All Rights Reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+// in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the
+// License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+// express or implied. See the License for the specific language governing permissions and
+// limitations underthe License.
+
+//! expression module
+//!
+//! Parses string expressions into a syntax tree and evaluates the tree against the variables
+//! and functions provided by an evaluation context.
+//!
+//! Expressions accepted by qoqo_calculator evaluate to the same values, in particular a unary
+//! sign binds tighter than `^` (`-2^2 == 4`) and `sign(0) == 1`. The grammar extends the one of
+//! qoqo_calculator with comparisons, logical operators, conditionals, factorials, chained powers,
+//! more built-in functions and the constants `pi` and `e`, which are used when no variable of
+//! that name is set and before the resolver is asked. Unlike qoqo_calculator, values that are
+//! not separated by an operator (`2 3` or `2(3)`) are a parsing error instead of evaluating
+//! to the last value.
+
+use num_complex::Complex;
+use pyo3::PyErr;
+use std::collections::{BTreeSet, HashMap};
+use std::f64::consts::{E, PI};
+use std::fmt;
+use thiserror::Error;
+
+/// Errors that can occur while parsing or evaluating an expression.
+#[derive(Error, Debug)]
+pub enum ExpressionError {
+    /// The expression is not syntactically valid.
+    #[error("Parsing error at position {position}: {msg}")]
+    ParsingError { msg: String, position: usize },
+    /// A variable used in the expression has no value.
+    #[error("Variable {name} is not set")]
+    VariableNotSet { name: String },
+    /// A function used in the expression is neither built in nor registered.
+    #[error("Function {name} not found")]
+    FunctionNotFound { name: String },
+    /// A function is called with the wrong number of arguments.
+    #[error("Function {name} expects {expected} argument(s) but got {found}")]
+    WrongNumberOfArguments {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// Division by zero during evaluation.
+    #[error("Division by zero")]
+    DivisionByZero,
+    /// A registered Python function raised an exception.
+    #[error("Function {name} raised an exception")]
+    FunctionFailed { name: String, err: PyErr },
+    /// The resolver of unknown variables raised an exception.
+    #[error("Resolver raised an exception for variable {name}")]
+    ResolverFailed { name: String, err: PyErr },
+    /// A statement of a multi-statement script failed.
+    #[error("Statement {index} ({statement}) failed: {err}")]
+    StatementFailed {
+        index: usize,
+        statement: String,
+        err: Box<ExpressionError>,
+    },
+    /// A function that is only defined for real numbers got a complex argument.
+    #[error("Function {name} is not defined for complex arguments")]
+    ComplexArgument { name: String },
+    /// The expression of a derived variable could not be evaluated.
+    #[error("Definition of {name} failed: {err}")]
+    DefinitionFailed {
+        name: String,
+        err: Box<ExpressionError>,
+    },
+    /// Named definitions reference each other in a cycle.
+    #[error("Circular definition: {}", .cycle.join(" -> "))]
+    CircularDefinition { cycle: Vec<String> },
+    /// An operator that is only defined for real numbers got a complex operand.
+    #[error("Operator {op} is not defined for complex operands")]
+    ComplexOperand { op: String },
+    /// A complex variable is used in a real-valued evaluation.
+    #[error("Variable {name} is complex")]
+    ComplexVariable { name: String },
+    /// A real-valued evaluation resulted in a complex number.
+    #[error("Result {value} is not real")]
+    NonRealResult { value: Complex<f64> },
+    /// A script does not contain any statement.
+    #[error("No expression to evaluate")]
+    EmptyScript,
+    /// A script tried to assign a value to a read-only constant.
+    #[error("Can not overwrite constant {name}")]
+    ReadOnlyVariable { name: String },
+    /// A value or operation can not be evaluated in high-precision mode.
+    #[error("High-precision evaluation failed: {msg}")]
+    PrecisionError { msg: String },
+    /// A partially evaluated expression contains a construct qoqo_calculator can not read.
+    #[error("Partial result can not be read by qoqo_calculator: {construct}")]
+    Unportable { construct: String },
+}
+
+impl ExpressionError {
+    /// Return the Python exception that caused the error, if any.
+    pub fn python_error(&self) -> Option<&PyErr> {
+        match self {
+            ExpressionError::FunctionFailed { err, .. } => Some(err),
+            ExpressionError::ResolverFailed { err, .. } => Some(err),
+            ExpressionError::StatementFailed { err, .. } => err.python_error(),
+            ExpressionError::DefinitionFailed { err, .. } => err.python_error(),
+            _ => None,
+        }
+    }
+}
+
+/// Unary operators of the expression grammar.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOperator {
+    Plus,
+    Minus,
+    Not,
+}
+
+/// Binary operators of the expression grammar.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+}
+
+/// Kind of a node in the expression syntax tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpressionKind {
+    /// Numeric literal.
+    Number(f64),
+    /// Named variable or constant.
+    Variable(String),
+    /// Unary operation.
+    Unary {
+        op: UnaryOperator,
+        operand: Box<Expression>,
+    },
+    /// Binary operation.
+    Binary {
+        op: BinaryOperator,
+        lhs: Box<Expression>,
+        rhs: Box<Expression>,
+    },
+    /// Function call.
+    Function {
+        name: String,
+        arguments: Vec<Expression>,
+    },
+    /// Conditional `condition ? then : otherwise`.
+    Conditional {
+        condition: Box<Expression>,
+        then: Box<Expression>,
+        otherwise: Box<Expression>,
+    },
+}
+
+/// Node in the expression syntax tree together with its byte span in the source string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Expression {
+    /// Call `f` for this node and all nodes below it, parents before children.
+    pub fn walk<'a>(&'a self, f: &mut dyn FnMut(&'a Expression)) {
+        f(self);
+        match &self.kind {
+            ExpressionKind::Number(_) | ExpressionKind::Variable(_) => (),
+            ExpressionKind::Unary { operand, .. } => operand.walk(f),
+            ExpressionKind::Binary { lhs, rhs, .. } => {
+                lhs.walk(f);
+                rhs.walk(f);
+            }
+            ExpressionKind::Function { arguments, .. } => {
+                for argument in arguments {
+                    argument.walk(f);
+                }
+            }
+            ExpressionKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                condition.walk(f);
+                then.walk(f);
+                otherwise.walk(f);
+            }
+        }
+    }
+
+    /// Return the names of all variables referenced in the expression.
+    pub fn variable_names(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        self.walk(&mut |node| {
+            if let ExpressionKind::Variable(name) = &node.kind {
+                names.insert(name.clone());
+            }
+        });
+        names
+    }
+
+    /// Return a short name of the kind of the node.
+    pub fn kind_name(&self) -> &'static str {
+        match &self.kind {
+            ExpressionKind::Number(_) => "number",
+            ExpressionKind::Variable(_) => "variable",
+            ExpressionKind::Unary { .. } => "unary",
+            ExpressionKind::Binary { .. } => "binary",
+            ExpressionKind::Function { .. } => "function",
+            ExpressionKind::Conditional { .. } => "conditional",
+        }
+    }
+
+    /// Return the binding strength of the node when it is printed.
+    fn precedence(&self) -> u8 {
+        match &self.kind {
+            ExpressionKind::Number(value) if *value < 0.0 => 9,
+            ExpressionKind::Number(_)
+            | ExpressionKind::Variable(_)
+            | ExpressionKind::Function { .. } => 10,
+            ExpressionKind::Unary {
+                op: UnaryOperator::Not,
+                ..
+            } => 4,
+            ExpressionKind::Unary { .. } => 9,
+            ExpressionKind::Binary { op, .. } => op.precedence(),
+            ExpressionKind::Conditional { .. } => 1,
+        }
+    }
+
+    /// Create a node of the given kind with the span of this node.
+    fn with_kind(&self, kind: ExpressionKind) -> Expression {
+        Expression {
+            kind,
+            start: self.start,
+            end: self.end,
+        }
+    }
+}
+
+impl BinaryOperator {
+    /// Return the binding strength of the operator.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOperator::Or => 2,
+            BinaryOperator::And => 3,
+            BinaryOperator::Less
+            | BinaryOperator::LessEqual
+            | BinaryOperator::Greater
+            | BinaryOperator::GreaterEqual
+            | BinaryOperator::Equal
+            | BinaryOperator::NotEqual => 5,
+            BinaryOperator::Add | BinaryOperator::Subtract => 6,
+            BinaryOperator::Multiply | BinaryOperator::Divide => 7,
+            BinaryOperator::Power => 8,
+        }
+    }
+
+    /// Return the symbol of the operator.
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Power => "^",
+            BinaryOperator::Less => "<",
+            BinaryOperator::LessEqual => "<=",
+            BinaryOperator::Greater => ">",
+            BinaryOperator::GreaterEqual => ">=",
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::And => "&&",
+            BinaryOperator::Or => "||",
+        }
+    }
+}
+
+/// Write a node, wrapped in parentheses when it binds weaker than required.
+///
+/// Signs, negative numbers and powers are always wrapped, because qoqo_calculator
+/// accepts only a single sign or power without parentheses.
+fn write_operand(f: &mut fmt::Formatter, operand: &Expression, min_precedence: u8) -> fmt::Result {
+    let signed_or_power = match &operand.kind {
+        ExpressionKind::Number(value) => *value < 0.0,
+        ExpressionKind::Unary { op, .. } => *op != UnaryOperator::Not,
+        ExpressionKind::Binary { op, .. } => *op == BinaryOperator::Power,
+        _ => false,
+    };
+    if signed_or_power || operand.precedence() < min_precedence {
+        write!(f, "({})", operand)
+    } else {
+        write!(f, "{}", operand)
+    }
+}
+
+impl fmt::Display for Expression {
+    /// Print the expression in a form that can be parsed again.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            // Overflowing literals are the only non-finite numbers simplify keeps
+            ExpressionKind::Number(value) if value.is_infinite() => {
+                write!(f, "{}1e400", if *value < 0.0 { "-" } else { "" })
+            }
+            // Exponent notation keeps very large and very small numbers short
+            ExpressionKind::Number(value)
+                if *value != 0.0 && !(1e-4..1e16).contains(&value.abs()) =>
+            {
+                write!(f, "{:e}", value)
+            }
+            ExpressionKind::Number(value) => write!(f, "{}", value),
+            ExpressionKind::Variable(name) => write!(f, "{}", name),
+            ExpressionKind::Unary { op, operand } => {
+                match op {
+                    UnaryOperator::Plus => write!(f, "+")?,
+                    UnaryOperator::Minus => write!(f, "-")?,
+                    UnaryOperator::Not => {
+                        write!(f, "not ")?;
+                        return write_operand(f, operand, 4);
+                    }
+                }
+                write_operand(f, operand, 9)
+            }
+            ExpressionKind::Binary { op, lhs, rhs } => {
+                let precedence = op.precedence();
+                if *op == BinaryOperator::Power {
+                    write_operand(f, lhs, precedence + 1)?;
+                    write!(f, " {} ", op.symbol())?;
+                    write_operand(f, rhs, precedence)
+                } else {
+                    write_operand(f, lhs, precedence)?;
+                    write!(f, " {} ", op.symbol())?;
+                    write_operand(f, rhs, precedence + 1)
+                }
+            }
+            ExpressionKind::Function { name, arguments } => {
+                write!(f, "{}(", name)?;
+                for (index, argument) in arguments.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", argument)?;
+                }
+                write!(f, ")")
+            }
+            ExpressionKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                write_operand(f, condition, 2)?;
+                write!(f, " ? {} : {}", then, otherwise)
+            }
+        }
+    }
+}
+
+/// Statement of a multi-statement script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
+    /// Assignment `name = expression` storing the value in a variable.
+    Assignment { name: String, value: Expression },
+    /// Expression whose value is returned when it is the last statement.
+    Expression(Expression),
+}
+
+/// Statement of a script together with its byte span in the source string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Statement {
+    /// Return the expression evaluated by the statement.
+    pub fn expression(&self) -> &Expression {
+        match &self.kind {
+            StatementKind::Assignment { value, .. } => value,
+            StatementKind::Expression(expression) => expression,
+        }
+    }
+}
+
+/// Source of variable values and user defined functions used during evaluation.
+pub trait EvaluationContext {
+    /// Return the value of a variable, returning None when the variable is unknown.
+    fn variable(&self, name: &str) -> Option<Result<f64, ExpressionError>>;
+
+    /// Return the complex value of a variable, returning None when the variable is unknown.
+    fn complex_variable(&self, name: &str) -> Option<Result<Complex<f64>, ExpressionError>> {
+        self.variable(name)
+            .map(|value| value.map(|value| Complex::new(value, 0.0)))
+    }
+
+    /// Look up a variable that is neither known to the context nor a built-in constant,
+    /// returning None when it can not be resolved.
+    fn resolve_variable(&self, _name: &str) -> Option<Result<f64, ExpressionError>> {
+        None
+    }
+
+    /// Return the number of arguments of a user defined function if it exists.
+    fn function_arity(&self, name: &str) -> Option<usize>;
+
+    /// Call a user defined function, returning None when no such function exists.
+    fn call_function(&self, name: &str, arguments: &[f64]) -> Option<Result<f64, ExpressionError>>;
+
+    /// Call a user defined function with complex arguments,
+    /// returning None when no such function exists.
+    fn call_function_complex(
+        &self,
+        name: &str,
+        arguments: &[Complex<f64>],
+    ) -> Option<Result<Complex<f64>, ExpressionError>>;
+}
+
+/// Return the number of arguments of a built-in function.
+///
+/// # Arguments
+///
+/// * `name` - name of the function
+///
+/// # Returns
+///
+/// `Option<usize>` - number of arguments or None when there is no such built-in function
+///
+pub fn builtin_arity(name: &str) -> Option<usize> {
+    match name {
+        "sin" | "cos" | "tan" | "asin" | "acos" | "atan" | "sinh" | "cosh" | "tanh" | "asinh"
+        | "acosh" | "atanh" | "arsinh" | "arcosh" | "artanh" | "exp" | "exp2" | "expm1" | "log"
+        | "ln" | "log10" | "log2" | "log1p" | "sqrt" | "cbrt" | "abs" | "sign" | "signum"
+        | "floor" | "ceil" | "round" | "trunc" | "fract" | "recip" | "conj" | "real" | "imag"
+        | "arg" | "erf" | "tgamma" | "lgamma" | "factorial" | "delta" | "theta" | "parity" => {
+            Some(1)
+        }
+        "atan2" | "hypot" | "pow" | "min" | "max" => Some(2),
+        "clamp" => Some(3),
+        _ => None,
+    }
+}
+
+/// Evaluate a built-in function.
+fn call_builtin(name: &str, arguments: &[f64]) -> Option<f64> {
+    let x = *arguments.first()?;
+    let value = match name {
+        "sin" => x.sin(),
+        "cos" => x.cos(),
+        "tan" => x.tan(),
+        "asin" => x.asin(),
+        "acos" => x.acos(),
+        "atan" => x.atan(),
+        "sinh" => x.sinh(),
+        "cosh" => x.cosh(),
+        "tanh" => x.tanh(),
+        "asinh" | "arsinh" => x.asinh(),
+        "acosh" | "arcosh" => x.acosh(),
+        "atanh" | "artanh" => x.atanh(),
+        "exp" => x.exp(),
+        "exp2" => x.exp2(),
+        "expm1" => x.exp_m1(),
+        "log" | "ln" => x.ln(),
+        "log10" => x.log10(),
+        "log2" => x.log2(),
+        "log1p" => x.ln_1p(),
+        "sqrt" => x.sqrt(),
+        "cbrt" => x.cbrt(),
+        "abs" => x.abs(),
+        "sign" | "signum" => x.signum(),
+        "floor" => x.floor(),
+        "ceil" => x.ceil(),
+        "round" => x.round(),
+        "trunc" => x.trunc(),
+        "fract" => x.fract(),
+        "recip" => x.recip(),
+        "conj" | "real" => x,
+        "imag" => 0.0,
+        "arg" => 0.0_f64.atan2(x),
+        "erf" => libm::erf(x),
+        "tgamma" => libm::tgamma(x),
+        "lgamma" => libm::lgamma(x),
+        "factorial" => libm::tgamma(x + 1.0),
+        "delta" => {
+            if x.abs() < f64::EPSILON {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        "theta" => {
+            if x.abs() < f64::EPSILON {
+                0.5
+            } else if x < 0.0 {
+                0.0
+            } else {
+                1.0
+            }
+        }
+        "parity" => {
+            if (x + 0.5).floor() % 2.0 == 0.0 {
+                1.0
+            } else {
+                -1.0
+            }
+        }
+        "atan2" => x.atan2(*arguments.get(1)?),
+        "hypot" => x.hypot(*arguments.get(1)?),
+        "pow" => x.powf(*arguments.get(1)?),
+        "min" => x.min(*arguments.get(1)?),
+        "max" => x.max(*arguments.get(1)?),
+        "clamp" => x.max(*arguments.get(1)?).min(*arguments.get(2)?),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Functions qoqo_calculator can evaluate.
+const QOQO_FUNCTIONS: [&str; 35] = [
+    "sin", "cos", "abs", "tan", "acos", "asin", "atan", "cosh", "sinh", "tanh", "acosh", "asinh",
+    "atanh", "arcosh", "arsinh", "artanh", "exp", "exp2", "expm1", "log", "log10", "sqrt", "cbrt",
+    "ceil", "floor", "fract", "round", "sign", "delta", "theta", "atan2", "hypot", "pow", "max",
+    "min",
+];
+
+/// Check that qoqo_calculator can read the printed form of an expression.
+///
+/// Conditionals, comparisons, logical operators, factorials, user defined functions,
+/// built-in functions qoqo_calculator can not evaluate and names starting with `_`
+/// are rejected.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is checked
+/// * `context` - context providing the user defined functions
+///
+/// # Returns
+///
+/// `Result<(), ExpressionError>` - Ok or the first construct qoqo_calculator can not read
+///
+pub fn check_portable(
+    expression: &Expression,
+    context: &dyn EvaluationContext,
+) -> Result<(), ExpressionError> {
+    let unportable = |construct: String| Err(ExpressionError::Unportable { construct });
+    match &expression.kind {
+        ExpressionKind::Number(_) => Ok(()),
+        ExpressionKind::Variable(name) if name.starts_with('_') => {
+            unportable(format!("variable {}", name))
+        }
+        ExpressionKind::Variable(_) => Ok(()),
+        ExpressionKind::Unary {
+            op: UnaryOperator::Not,
+            ..
+        } => unportable("logical operator not".to_string()),
+        ExpressionKind::Unary { operand, .. } => check_portable(operand, context),
+        ExpressionKind::Binary { op, lhs, rhs } => {
+            if op.precedence() < BinaryOperator::Add.precedence() {
+                return unportable(format!("operator {}", op.symbol()));
+            }
+            check_portable(lhs, context)?;
+            check_portable(rhs, context)
+        }
+        ExpressionKind::Function { name, arguments } => {
+            if context.function_arity(name).is_some() || !QOQO_FUNCTIONS.contains(&name.as_str()) {
+                return unportable(format!("function {}", name));
+            }
+            arguments
+                .iter()
+                .try_for_each(|argument| check_portable(argument, context))
+        }
+        ExpressionKind::Conditional { .. } => unportable("conditional".to_string()),
+    }
+}
+
+/// Return true when a name is reserved by the expression grammar.
+///
+/// `and`, `or` and `not` are operators, `if` is reserved for conditionals.
+pub fn is_keyword(name: &str) -> bool {
+    matches!(name, "and" | "or" | "not" | "if")
+}
+
+/// Return the value of a built-in constant.
+pub fn builtin_constant(name: &str) -> Option<f64> {
+    match name {
+        "pi" => Some(PI),
+        "e" => Some(E),
+        _ => None,
+    }
+}
+
+/// Tokens of the expression grammar.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Identifier(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Caret,
+    LeftParen,
+    RightParen,
+    Comma,
+    Assign,
+    Separator,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+    Not,
+    Question,
+    Colon,
+    Exclamation,
+    End,
+}
+
+/// Token together with its byte span in the source string.
+#[derive(Debug, Clone)]
+struct Lexeme {
+    token: Token,
+    start: usize,
+    end: usize,
+}
+
+/// Split an expression string into tokens.
+fn tokenize(input: &str) -> Result<Vec<Lexeme>, ExpressionError> {
+    let bytes = input.as_bytes();
+    let mut lexemes = Vec::new();
+    let mut position = 0;
+    let mut depth = 0_usize;
+    while position < bytes.len() {
+        let current = bytes[position];
+        let start = position;
+        let token = match current {
+            b'\n' if depth == 0 => {
+                position += 1;
+                Token::Separator
+            }
+            b' ' | b'\t' | b'\r' | b'\n' => {
+                position += 1;
+                continue;
+            }
+            b'#' => {
+                while position < bytes.len() && bytes[position] != b'\n' {
+                    position += 1;
+                }
+                continue;
+            }
+            b'0'..=b'9' | b'.' => {
+                while position < bytes.len()
+                    && (bytes[position].is_ascii_digit() || bytes[position] == b'.')
+                {
+                    position += 1;
+                }
+                if position < bytes.len() && (bytes[position] == b'e' || bytes[position] == b'E') {
+                    let mut exponent = position + 1;
+                    if exponent < bytes.len()
+                        && (bytes[exponent] == b'+' || bytes[exponent] == b'-')
+                    {
+                        exponent += 1;
+                    }
+                    if exponent < bytes.len() && bytes[exponent].is_ascii_digit() {
+                        position = exponent;
+                        while position < bytes.len() && bytes[position].is_ascii_digit() {
+                            position += 1;
+                        }
+                    }
+                }
+                let literal = &input[start..position];
+                let value = literal
+                    .parse::<f64>()
+                    .map_err(|_| ExpressionError::ParsingError {
+                        msg: format!("Invalid number {}", literal),
+                        position: start,
+                    })?;
+                Token::Number(value)
+            }
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                while position < bytes.len()
+                    && (bytes[position].is_ascii_alphanumeric() || bytes[position] == b'_')
+                {
+                    position += 1;
+                }
+                match &input[start..position] {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    identifier => Token::Identifier(identifier.to_string()),
+                }
+            }
+            _ => {
+                if let Some(token) = two_character_token(current, bytes.get(position + 1)) {
+                    position += 2;
+                    lexemes.push(Lexeme {
+                        token,
+                        start,
+                        end: position,
+                    });
+                    continue;
+                }
+                let token = match current {
+                    b'+' => Token::Plus,
+                    b'-' => Token::Minus,
+                    b'*' => Token::Star,
+                    b'/' => Token::Slash,
+                    b'^' => Token::Caret,
+                    b'(' => {
+                        depth += 1;
+                        Token::LeftParen
+                    }
+                    b')' => {
+                        depth = depth.saturating_sub(1);
+                        Token::RightParen
+                    }
+                    b',' => Token::Comma,
+                    b'=' => Token::Assign,
+                    b';' => Token::Separator,
+                    b'<' => Token::Less,
+                    b'>' => Token::Greater,
+                    b'!' => Token::Exclamation,
+                    b'?' => Token::Question,
+                    b':' => Token::Colon,
+                    _ => {
+                        return Err(ExpressionError::ParsingError {
+                            msg: format!(
+                                "Unexpected character {:?}",
+                                input[start..].chars().next().unwrap_or_default()
+                            ),
+                            position: start,
+                        })
+                    }
+                };
+                position += 1;
+                token
+            }
+        };
+        lexemes.push(Lexeme {
+            token,
+            start,
+            end: position,
+        });
+    }
+    lexemes.push(Lexeme {
+        token: Token::End,
+        start: input.len(),
+        end: input.len(),
+    });
+    Ok(lexemes)
+}
+
+/// Return the operator token made of two characters, if any.
+fn two_character_token(first: u8, second: Option<&u8>) -> Option<Token> {
+    match (first, *second?) {
+        (b'*', b'*') => Some(Token::Caret),
+        (b'<', b'=') => Some(Token::LessEqual),
+        (b'>', b'=') => Some(Token::GreaterEqual),
+        (b'=', b'=') => Some(Token::Equal),
+        (b'!', b'=') => Some(Token::NotEqual),
+        (b'&', b'&') => Some(Token::And),
+        (b'|', b'|') => Some(Token::Or),
+        _ => None,
+    }
+}
+
+/// Maximal nesting depth of parentheses, function calls, signs and powers.
+///
+/// Bounds the recursion of the parser and of all functions walking the syntax tree,
+/// so deeply nested untrusted input fails with a parsing error instead of overflowing the stack.
+const MAX_NESTING: usize = 128;
+
+/// Recursive descent parser over a list of tokens.
+struct Parser {
+    lexemes: Vec<Lexeme>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    /// Return the current token without consuming it.
+    fn peek(&self) -> &Lexeme {
+        &self.lexemes[self.position]
+    }
+
+    /// Consume and return the current token.
+    fn advance(&mut self) -> Lexeme {
+        let lexeme = self.lexemes[self.position].clone();
+        if lexeme.token != Token::End {
+            self.position += 1;
+        }
+        lexeme
+    }
+
+    /// Create a parsing error at the current token.
+    fn error(&self, msg: &str) -> ExpressionError {
+        ExpressionError::ParsingError {
+            msg: msg.to_string(),
+            position: self.peek().start,
+        }
+    }
+
+    /// Run a parsing step one nesting level deeper.
+    fn nested<T>(
+        &mut self,
+        step: impl FnOnce(&mut Parser) -> Result<T, ExpressionError>,
+    ) -> Result<T, ExpressionError> {
+        if self.depth >= MAX_NESTING {
+            return Err(self.error("Expression is nested too deeply"));
+        }
+        self.depth += 1;
+        let result = step(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Consume the current token if it is the expected one, otherwise return an error.
+    fn expect(&mut self, token: Token, msg: &str) -> Result<Lexeme, ExpressionError> {
+        if self.peek().token == token {
+            Ok(self.advance())
+        } else {
+            Err(self.error(msg))
+        }
+    }
+
+    /// expression := conditional
+    fn parse_expression(&mut self) -> Result<Expression, ExpressionError> {
+        self.nested(|parser| parser.parse_conditional())
+    }
+
+    /// conditional := or ('?' expression ':' expression)?
+    fn parse_conditional(&mut self) -> Result<Expression, ExpressionError> {
+        let condition = self.parse_or()?;
+        if self.peek().token != Token::Question {
+            return Ok(condition);
+        }
+        self.advance();
+        let then = self.parse_expression()?;
+        self.expect(Token::Colon, "Expected ':' in conditional expression")?;
+        let otherwise = self.parse_expression()?;
+        Ok(Expression {
+            start: condition.start,
+            end: otherwise.end,
+            kind: ExpressionKind::Conditional {
+                condition: Box::new(condition),
+                then: Box::new(then),
+                otherwise: Box::new(otherwise),
+            },
+        })
+    }
+
+    /// or := and (('||' | 'or') and)*
+    fn parse_or(&mut self) -> Result<Expression, ExpressionError> {
+        let mut lhs = self.parse_and()?;
+        while self.peek().token == Token::Or {
+            self.advance();
+            let rhs = self.parse_and()?;
+            lhs = binary(BinaryOperator::Or, lhs, rhs);
+        }
+        Ok(lhs)
+    }
+
+    /// and := not (('&&' | 'and') not)*
+    fn parse_and(&mut self) -> Result<Expression, ExpressionError> {
+        let mut lhs = self.parse_not()?;
+        while self.peek().token == Token::And {
+            self.advance();
+            let rhs = self.parse_not()?;
+            lhs = binary(BinaryOperator::And, lhs, rhs);
+        }
+        Ok(lhs)
+    }
+
+    /// not := ('!' | 'not') not | comparison
+    fn parse_not(&mut self) -> Result<Expression, ExpressionError> {
+        if !matches!(self.peek().token, Token::Not | Token::Exclamation) {
+            return self.parse_comparison();
+        }
+        let start = self.advance().start;
+        let operand = self.nested(|parser| parser.parse_not())?;
+        Ok(Expression {
+            start,
+            end: operand.end,
+            kind: ExpressionKind::Unary {
+                op: UnaryOperator::Not,
+                operand: Box::new(operand),
+            },
+        })
+    }
+
+    /// comparison := additive (('<' | '<=' | '>' | '>=' | '==' | '!=') additive)*
+    fn parse_comparison(&mut self) -> Result<Expression, ExpressionError> {
+        let mut lhs = self.parse_additive()?;
+        loop {
+            let op = match self.peek().token {
+                Token::Less => BinaryOperator::Less,
+                Token::LessEqual => BinaryOperator::LessEqual,
+                Token::Greater => BinaryOperator::Greater,
+                Token::GreaterEqual => BinaryOperator::GreaterEqual,
+                Token::Equal => BinaryOperator::Equal,
+                Token::NotEqual => BinaryOperator::NotEqual,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            let rhs = self.parse_additive()?;
+            lhs = binary(op, lhs, rhs);
+        }
+    }
+
+    /// additive := multiplicative (('+' | '-') multiplicative)*
+    fn parse_additive(&mut self) -> Result<Expression, ExpressionError> {
+        let mut lhs = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek().token {
+                Token::Plus => BinaryOperator::Add,
+                Token::Minus => BinaryOperator::Subtract,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            let rhs = self.parse_multiplicative()?;
+            lhs = binary(op, lhs, rhs);
+        }
+    }
+
+    /// multiplicative := power (('*' | '/') power)*
+    fn parse_multiplicative(&mut self) -> Result<Expression, ExpressionError> {
+        let mut lhs = self.parse_power()?;
+        loop {
+            let op = match self.peek().token {
+                Token::Star => BinaryOperator::Multiply,
+                Token::Slash => BinaryOperator::Divide,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            let rhs = self.parse_power()?;
+            lhs = binary(op, lhs, rhs);
+        }
+    }
+
+    /// power := unary ('^' power)?
+    ///
+    /// As in qoqo_calculator the sign binds tighter than the power, `-2^2` is 4.
+    fn parse_power(&mut self) -> Result<Expression, ExpressionError> {
+        let base = self.parse_unary()?;
+        if self.peek().token == Token::Caret {
+            self.advance();
+            let exponent = self.nested(|parser| parser.parse_power())?;
+            Ok(binary(BinaryOperator::Power, base, exponent))
+        } else {
+            Ok(base)
+        }
+    }
+
+    /// unary := ('+' | '-') unary | factorial
+    fn parse_unary(&mut self) -> Result<Expression, ExpressionError> {
+        let op = match self.peek().token {
+            Token::Plus => UnaryOperator::Plus,
+            Token::Minus => UnaryOperator::Minus,
+            _ => return self.parse_factorial(),
+        };
+        let start = self.advance().start;
+        let operand = self.nested(|parser| parser.parse_unary())?;
+        Ok(Expression {
+            start,
+            end: operand.end,
+            kind: ExpressionKind::Unary {
+                op,
+                operand: Box::new(operand),
+            },
+        })
+    }
+
+    /// factorial := primary '!'?
+    ///
+    /// The postfix factorial is represented as a call of the built-in function `factorial`.
+    fn parse_factorial(&mut self) -> Result<Expression, ExpressionError> {
+        let operand = self.parse_primary()?;
+        if self.peek().token != Token::Exclamation {
+            return Ok(operand);
+        }
+        let end = self.advance().end;
+        Ok(Expression {
+            start: operand.start,
+            end,
+            kind: ExpressionKind::Function {
+                name: "factorial".to_string(),
+                arguments: vec![operand],
+            },
+        })
+    }
+
+    /// primary := number | identifier | identifier '(' arguments ')' | '(' expression ')'
+    fn parse_primary(&mut self) -> Result<Expression, ExpressionError> {
+        let lexeme = self.advance();
+        match lexeme.token {
+            Token::Number(value) => Ok(Expression {
+                kind: ExpressionKind::Number(value),
+                start: lexeme.start,
+                end: lexeme.end,
+            }),
+            Token::Identifier(name) => {
+                if self.peek().token != Token::LeftParen {
+                    return Ok(Expression {
+                        kind: ExpressionKind::Variable(name),
+                        start: lexeme.start,
+                        end: lexeme.end,
+                    });
+                }
+                self.advance();
+                let mut arguments = Vec::new();
+                if self.peek().token != Token::RightParen {
+                    loop {
+                        arguments.push(self.parse_expression()?);
+                        if self.peek().token == Token::Comma {
+                            self.advance();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                let close = self.expect(Token::RightParen, "Expected ')' after arguments")?;
+                Ok(Expression {
+                    kind: ExpressionKind::Function { name, arguments },
+                    start: lexeme.start,
+                    end: close.end,
+                })
+            }
+            Token::LeftParen => {
+                let inner = self.parse_expression()?;
+                let close = self.expect(Token::RightParen, "Expected ')'")?;
+                Ok(Expression {
+                    start: lexeme.start,
+                    end: close.end,
+                    ..inner
+                })
+            }
+            Token::End => Err(ExpressionError::ParsingError {
+                msg: "Unexpected end of expression".to_string(),
+                position: lexeme.start,
+            }),
+            _ => Err(ExpressionError::ParsingError {
+                msg: "Unexpected token".to_string(),
+                position: lexeme.start,
+            }),
+        }
+    }
+}
+
+impl Parser {
+    /// statement := identifier '=' expression | expression
+    fn parse_statement(&mut self) -> Result<Statement, ExpressionError> {
+        let lexeme = self.peek().clone();
+        if let Token::Identifier(name) = lexeme.token {
+            if self.lexemes[self.position + 1].token == Token::Assign {
+                if is_keyword(&name) {
+                    return Err(ExpressionError::ParsingError {
+                        msg: format!("Can not assign to reserved word {}", name),
+                        position: lexeme.start,
+                    });
+                }
+                self.advance();
+                self.advance();
+                let value = self.parse_expression()?;
+                return Ok(Statement {
+                    start: lexeme.start,
+                    end: value.end,
+                    kind: StatementKind::Assignment { name, value },
+                });
+            }
+        }
+        let expression = self.parse_expression()?;
+        Ok(Statement {
+            start: expression.start,
+            end: expression.end,
+            kind: StatementKind::Expression(expression),
+        })
+    }
+}
+
+/// Create a binary operation node spanning both operands.
+fn binary(op: BinaryOperator, lhs: Expression, rhs: Expression) -> Expression {
+    Expression {
+        start: lhs.start,
+        end: rhs.end,
+        kind: ExpressionKind::Binary {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+    }
+}
+
+/// Parse a string expression into a syntax tree.
+///
+/// # Arguments
+///
+/// * `input` - Expression that is parsed
+///
+/// # Returns
+///
+/// `Result<Expression, ExpressionError>` - root node of the syntax tree or parsing error
+///
+pub fn parse(input: &str) -> Result<Expression, ExpressionError> {
+    let mut parser = Parser {
+        lexemes: tokenize(input)?,
+        position: 0,
+        depth: 0,
+    };
+    let expression = parser.parse_expression()?;
+    if parser.peek().token != Token::End {
+        return Err(parser.error("Unexpected token after end of expression"));
+    }
+    Ok(expression)
+}
+
+/// Parse a script of statements separated by `;` or newlines.
+///
+/// Newlines inside parentheses do not separate statements,
+/// text from `#` to the end of a line is a comment.
+///
+/// # Arguments
+///
+/// * `input` - Script that is parsed
+///
+/// # Returns
+///
+/// `Result<Vec<Statement>, ExpressionError>` - parsed statements or parsing error
+///
+pub fn parse_script(input: &str) -> Result<Vec<Statement>, ExpressionError> {
+    let mut parser = Parser {
+        lexemes: tokenize(input)?,
+        position: 0,
+        depth: 0,
+    };
+    let mut statements = Vec::new();
+    loop {
+        while parser.peek().token == Token::Separator {
+            parser.advance();
+        }
+        if parser.peek().token == Token::End {
+            break;
+        }
+        statements.push(parser.parse_statement()?);
+        match parser.peek().token {
+            Token::Separator | Token::End => (),
+            _ => return Err(parser.error("Expected ';' or newline after statement")),
+        }
+    }
+    if statements.is_empty() {
+        return Err(ExpressionError::EmptyScript);
+    }
+    Ok(statements)
+}
+
+/// Collect the variables and functions referenced by a script.
+///
+/// Variables that are assigned by an earlier statement of the script are not included.
+///
+/// # Arguments
+///
+/// * `statements` - parsed statements of the script
+///
+/// # Returns
+///
+/// `(BTreeSet<String>, BTreeSet<String>)` - names of the referenced variables and functions
+///
+pub fn referenced_names(statements: &[Statement]) -> (BTreeSet<String>, BTreeSet<String>) {
+    let mut assigned = BTreeSet::new();
+    let mut variables = BTreeSet::new();
+    let mut functions = BTreeSet::new();
+    for statement in statements {
+        statement.expression().walk(&mut |node| match &node.kind {
+            ExpressionKind::Variable(name) if !assigned.contains(name) => {
+                variables.insert(name.clone());
+            }
+            ExpressionKind::Function { name, .. } => {
+                functions.insert(name.clone());
+            }
+            _ => (),
+        });
+        if let StatementKind::Assignment { name, .. } = &statement.kind {
+            assigned.insert(name.clone());
+        }
+    }
+    (variables, functions)
+}
+
+/// Order named definitions so that each definition comes after the definitions it references.
+///
+/// # Arguments
+///
+/// * `definitions` - syntax trees of the definitions by name
+///
+/// # Returns
+///
+/// `Result<Vec<String>, ExpressionError>` - names in evaluation order or the first cycle found
+///
+pub fn sort_definitions(
+    definitions: &HashMap<String, Expression>,
+) -> Result<Vec<String>, ExpressionError> {
+    let mut names: Vec<&String> = definitions.keys().collect();
+    names.sort();
+    let mut finished: HashMap<&str, bool> = HashMap::new();
+    let mut path: Vec<&str> = Vec::new();
+    let mut order: Vec<String> = Vec::with_capacity(definitions.len());
+    for name in names {
+        visit_definition(name, definitions, &mut finished, &mut path, &mut order)?;
+    }
+    Ok(order)
+}
+
+/// Depth-first visit of a definition and its dependencies used by `sort_definitions`.
+fn visit_definition<'a>(
+    name: &'a str,
+    definitions: &'a HashMap<String, Expression>,
+    finished: &mut HashMap<&'a str, bool>,
+    path: &mut Vec<&'a str>,
+    order: &mut Vec<String>,
+) -> Result<(), ExpressionError> {
+    match finished.get(name) {
+        Some(true) => return Ok(()),
+        Some(false) => {
+            let begin = path.iter().position(|entry| *entry == name).unwrap_or(0);
+            let mut cycle: Vec<String> = path[begin..]
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect();
+            cycle.push(name.to_string());
+            return Err(ExpressionError::CircularDefinition { cycle });
+        }
+        None => (),
+    }
+    finished.insert(name, false);
+    path.push(name);
+    if let Some(expression) = definitions.get(name) {
+        for dependency in expression.variable_names() {
+            if let Some((key, _)) = definitions.get_key_value(dependency.as_str()) {
+                visit_definition(key, definitions, finished, path, order)?;
+            }
+        }
+    }
+    path.pop();
+    finished.insert(name, true);
+    order.push(name.to_string());
+    Ok(())
+}
+
+/// Check that all functions called in an expression exist and get the right number of arguments.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is checked
+/// * `context` - context providing the user defined functions
+///
+pub fn check_functions(
+    expression: &Expression,
+    context: &dyn EvaluationContext,
+) -> Result<(), ExpressionError> {
+    match invalid_calls(expression, context).into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Return the errors of all function calls in an expression that can not be evaluated.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is checked
+/// * `context` - context providing the user defined functions
+///
+/// # Returns
+///
+/// `Vec<ExpressionError>` - FunctionNotFound and WrongNumberOfArguments errors, outer calls first
+///
+pub fn invalid_calls(
+    expression: &Expression,
+    context: &dyn EvaluationContext,
+) -> Vec<ExpressionError> {
+    let mut errors = Vec::new();
+    expression.walk(&mut |node| {
+        if let ExpressionKind::Function { name, arguments } = &node.kind {
+            if let Err(err) = check_arity(name, arguments.len(), context) {
+                errors.push(err);
+            }
+        }
+    });
+    errors
+}
+
+/// Check the number of arguments of a single function call.
+fn check_arity(
+    name: &str,
+    found: usize,
+    context: &dyn EvaluationContext,
+) -> Result<(), ExpressionError> {
+    let expected = context
+        .function_arity(name)
+        .or_else(|| builtin_arity(name))
+        .ok_or_else(|| ExpressionError::FunctionNotFound {
+            name: name.to_string(),
+        })?;
+    if expected == found {
+        Ok(())
+    } else {
+        Err(ExpressionError::WrongNumberOfArguments {
+            name: name.to_string(),
+            expected,
+            found,
+        })
+    }
+}
+
+/// Look up a variable in the context, falling back to the built-in constants
+/// and then to the resolver of the context.
+pub fn lookup_variable(
+    name: &str,
+    context: &dyn EvaluationContext,
+) -> Option<Result<f64, ExpressionError>> {
+    context
+        .variable(name)
+        .or_else(|| builtin_constant(name).map(Ok))
+        .or_else(|| context.resolve_variable(name))
+}
+
+/// Convert a truth value to a float, 1 for true and 0 for false.
+fn from_bool(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Apply a unary operator to a value.
+pub fn apply_unary(op: UnaryOperator, value: f64) -> f64 {
+    match op {
+        UnaryOperator::Plus => value,
+        UnaryOperator::Minus => -value,
+        UnaryOperator::Not => from_bool(value == 0.0),
+    }
+}
+
+/// Apply a binary operator to two values.
+pub fn apply_binary(op: BinaryOperator, lhs: f64, rhs: f64) -> Result<f64, ExpressionError> {
+    match op {
+        BinaryOperator::Add => Ok(lhs + rhs),
+        BinaryOperator::Subtract => Ok(lhs - rhs),
+        BinaryOperator::Multiply => Ok(lhs * rhs),
+        BinaryOperator::Divide => {
+            if rhs == 0.0 {
+                Err(ExpressionError::DivisionByZero)
+            } else {
+                Ok(lhs / rhs)
+            }
+        }
+        BinaryOperator::Power => Ok(lhs.powf(rhs)),
+        BinaryOperator::Less => Ok(from_bool(lhs < rhs)),
+        BinaryOperator::LessEqual => Ok(from_bool(lhs <= rhs)),
+        BinaryOperator::Greater => Ok(from_bool(lhs > rhs)),
+        BinaryOperator::GreaterEqual => Ok(from_bool(lhs >= rhs)),
+        BinaryOperator::Equal => Ok(from_bool(lhs == rhs)),
+        BinaryOperator::NotEqual => Ok(from_bool(lhs != rhs)),
+        BinaryOperator::And => Ok(from_bool(lhs != 0.0 && rhs != 0.0)),
+        BinaryOperator::Or => Ok(from_bool(lhs != 0.0 || rhs != 0.0)),
+    }
+}
+
+/// Return the value of a logical operator that is determined by its left operand alone.
+pub fn short_circuit(op: BinaryOperator, lhs: f64) -> Option<f64> {
+    match op {
+        BinaryOperator::And if lhs == 0.0 => Some(0.0),
+        BinaryOperator::Or if lhs != 0.0 => Some(1.0),
+        _ => None,
+    }
+}
+
+/// Call a user defined or built-in function with evaluated arguments.
+pub fn apply_function(
+    name: &str,
+    values: &[f64],
+    context: &dyn EvaluationContext,
+) -> Result<f64, ExpressionError> {
+    check_arity(name, values.len(), context)?;
+    match context.call_function(name, values) {
+        Some(result) => result,
+        None => call_builtin(name, values).ok_or_else(|| ExpressionError::FunctionNotFound {
+            name: name.to_string(),
+        }),
+    }
+}
+
+/// Evaluate a syntax tree to a float.
+///
+/// Comparisons and logical operators evaluate to 1 for true and 0 for false,
+/// every value except 0 counts as true. The right operand of `&&` and `||`
+/// and the branch of a conditional that is not taken are not evaluated.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is evaluated
+/// * `context` - context providing variable values and user defined functions
+///
+/// # Returns
+///
+/// `Result<f64, ExpressionError>` - value of the expression or evaluation error
+///
+pub fn evaluate(
+    expression: &Expression,
+    context: &dyn EvaluationContext,
+) -> Result<f64, ExpressionError> {
+    evaluate_observed(expression, context, &mut |_, _| ())
+}
+
+/// Evaluated node of a syntax tree together with its value.
+pub type TraceStep<'a> = (&'a Expression, f64);
+
+/// Evaluate a syntax tree to a float, recording the value of every evaluated node.
+///
+/// Nodes are recorded in evaluation order, operands before the operation using them.
+/// Nodes that are skipped by `&&`, `||` or a conditional are not recorded.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is evaluated
+/// * `context` - context providing variable values and user defined functions
+///
+/// # Returns
+///
+/// `Result<(f64, Vec<(&Expression, f64)>), ExpressionError>` - value of the expression
+///                                                           and the evaluated nodes
+///
+pub fn trace<'a>(
+    expression: &'a Expression,
+    context: &dyn EvaluationContext,
+) -> Result<(f64, Vec<TraceStep<'a>>), ExpressionError> {
+    let mut steps = Vec::new();
+    let value = evaluate_observed(expression, context, &mut |node, value| {
+        steps.push((node, value))
+    })?;
+    Ok((value, steps))
+}
+
+/// Evaluate a syntax tree, calling `observe` with every evaluated node and its value.
+fn evaluate_observed<'a>(
+    expression: &'a Expression,
+    context: &dyn EvaluationContext,
+    observe: &mut dyn FnMut(&'a Expression, f64),
+) -> Result<f64, ExpressionError> {
+    let value = match &expression.kind {
+        ExpressionKind::Number(value) => *value,
+        ExpressionKind::Variable(name) => lookup_variable(name, context)
+            .unwrap_or_else(|| Err(ExpressionError::VariableNotSet { name: name.clone() }))?,
+        ExpressionKind::Unary { op, operand } => {
+            apply_unary(*op, evaluate_observed(operand, context, observe)?)
+        }
+        ExpressionKind::Binary { op, lhs, rhs } => {
+            let lhs = evaluate_observed(lhs, context, observe)?;
+            match short_circuit(*op, lhs) {
+                Some(value) => value,
+                None => apply_binary(*op, lhs, evaluate_observed(rhs, context, observe)?)?,
+            }
+        }
+        ExpressionKind::Function { name, arguments } => {
+            let values = arguments
+                .iter()
+                .map(|argument| evaluate_observed(argument, context, observe))
+                .collect::<Result<Vec<f64>, ExpressionError>>()?;
+            apply_function(name, &values, context)?
+        }
+        ExpressionKind::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => {
+            if evaluate_observed(condition, context, observe)? != 0.0 {
+                evaluate_observed(then, context, observe)?
+            } else {
+                evaluate_observed(otherwise, context, observe)?
+            }
+        }
+    };
+    observe(expression, value);
+    Ok(value)
+}
+
+/// Return the number node of a folded value, None when the value is not finite.
+fn fold(value: f64) -> Option<ExpressionKind> {
+    if value.is_finite() {
+        Some(ExpressionKind::Number(value))
+    } else {
+        None
+    }
+}
+
+/// Substitute known variables and fold all constant sub-expressions into numbers.
+///
+/// Non-finite values can not be written as literals, so variables with non-finite values
+/// and sub-expressions evaluating to them are kept unchanged.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is simplified
+/// * `context` - context providing variable values and user defined functions
+///
+/// # Returns
+///
+/// `Result<Expression, ExpressionError>` - simplified syntax tree or evaluation error
+///
+pub fn simplify(
+    expression: &Expression,
+    context: &dyn EvaluationContext,
+) -> Result<Expression, ExpressionError> {
+    let kind = match &expression.kind {
+        ExpressionKind::Number(value) => ExpressionKind::Number(*value),
+        ExpressionKind::Variable(name) => match lookup_variable(name, context) {
+            Some(Ok(value)) if value.is_finite() => ExpressionKind::Number(value),
+            Some(Ok(_))
+            | Some(Err(ExpressionError::VariableNotSet { .. }))
+            | Some(Err(ExpressionError::ComplexVariable { .. }))
+            | None => ExpressionKind::Variable(name.clone()),
+            Some(Err(err)) => return Err(err),
+        },
+        ExpressionKind::Unary { op, operand } => {
+            let operand = simplify(operand, context)?;
+            let folded = match operand.kind {
+                ExpressionKind::Number(value) => fold(apply_unary(*op, value)),
+                _ => None,
+            };
+            folded.unwrap_or_else(|| ExpressionKind::Unary {
+                op: *op,
+                operand: Box::new(operand),
+            })
+        }
+        ExpressionKind::Binary { op, lhs, rhs } => {
+            let lhs = simplify(lhs, context)?;
+            if let ExpressionKind::Number(value) = lhs.kind {
+                if let Some(value) = short_circuit(*op, value) {
+                    return Ok(expression.with_kind(ExpressionKind::Number(value)));
+                }
+            }
+            let rhs = simplify(rhs, context)?;
+            let folded = match (&lhs.kind, &rhs.kind) {
+                (ExpressionKind::Number(l), ExpressionKind::Number(r)) => {
+                    fold(apply_binary(*op, *l, *r)?)
+                }
+                _ => None,
+            };
+            folded.unwrap_or_else(|| ExpressionKind::Binary {
+                op: *op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            })
+        }
+        ExpressionKind::Function { name, arguments } => {
+            let arguments = arguments
+                .iter()
+                .map(|argument| simplify(argument, context))
+                .collect::<Result<Vec<Expression>, ExpressionError>>()?;
+            let values: Vec<f64> = arguments
+                .iter()
+                .filter_map(|argument| match argument.kind {
+                    ExpressionKind::Number(value) => Some(value),
+                    _ => None,
+                })
+                .collect();
+            let folded = if values.len() == arguments.len() {
+                fold(apply_function(name, &values, context)?)
+            } else {
+                None
+            };
+            folded.unwrap_or_else(|| ExpressionKind::Function {
+                name: name.clone(),
+                arguments,
+            })
+        }
+        ExpressionKind::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => {
+            let condition = simplify(condition, context)?;
+            if let ExpressionKind::Number(value) = condition.kind {
+                let branch = if value != 0.0 { then } else { otherwise };
+                return simplify(branch, context);
+            }
+            // Errors in a branch that might not be taken must not fail the simplification
+            let then = simplify(then, context).unwrap_or_else(|_| (**then).clone());
+            let otherwise = simplify(otherwise, context).unwrap_or_else(|_| (**otherwise).clone());
+            ExpressionKind::Conditional {
+                condition: Box::new(condition),
+                then: Box::new(then),
+                otherwise: Box::new(otherwise),
+            }
+        }
+    };
+    Ok(expression.with_kind(kind))
+}
+
+/// Evaluate a built-in function for complex arguments.
+///
+/// Real arguments inside the domain of the real function are evaluated with the real function
+/// so that results stay exactly real.
+fn call_builtin_complex(
+    name: &str,
+    arguments: &[Complex<f64>],
+) -> Result<Complex<f64>, ExpressionError> {
+    if arguments.iter().all(|argument| argument.im == 0.0) {
+        let real_arguments: Vec<f64> = arguments.iter().map(|argument| argument.re).collect();
+        if let Some(value) = call_builtin(name, &real_arguments) {
+            if !value.is_nan() {
+                return Ok(Complex::new(value, 0.0));
+            }
+        }
+    }
+    let not_found = || ExpressionError::FunctionNotFound {
+        name: name.to_string(),
+    };
+    let z = *arguments.first().ok_or_else(not_found)?;
+    let value = match name {
+        "sin" => z.sin(),
+        "cos" => z.cos(),
+        "tan" => z.tan(),
+        "asin" => z.asin(),
+        "acos" => z.acos(),
+        "atan" => z.atan(),
+        "sinh" => z.sinh(),
+        "cosh" => z.cosh(),
+        "tanh" => z.tanh(),
+        "asinh" | "arsinh" => z.asinh(),
+        "acosh" | "arcosh" => z.acosh(),
+        "atanh" | "artanh" => z.atanh(),
+        "exp" => z.exp(),
+        "exp2" => z.expf(2.0),
+        "expm1" => z.exp() - 1.0,
+        "log" | "ln" => z.ln(),
+        "log10" => z.log(10.0),
+        "log2" => z.log(2.0),
+        "log1p" => (z + 1.0).ln(),
+        "sqrt" if z.im == 0.0 && z.re < 0.0 => Complex::new(0.0, (-z.re).sqrt()),
+        "sqrt" => z.sqrt(),
+        "cbrt" => z.powf(1.0 / 3.0),
+        "abs" => Complex::new(z.norm(), 0.0),
+        "sign" | "signum" => z / z.norm(),
+        "recip" => z.inv(),
+        "conj" => z.conj(),
+        "real" => Complex::new(z.re, 0.0),
+        "imag" => Complex::new(z.im, 0.0),
+        "arg" => Complex::new(z.arg(), 0.0),
+        "pow" => {
+            let exponent = *arguments.get(1).ok_or_else(not_found)?;
+            complex_power(z, exponent)
+        }
+        _ => {
+            return Err(match builtin_arity(name) {
+                Some(_) => ExpressionError::ComplexArgument {
+                    name: name.to_string(),
+                },
+                None => not_found(),
+            })
+        }
+    };
+    Ok(value)
+}
+
+/// Raise a complex number to a complex power, keeping real results exactly real.
+fn complex_power(base: Complex<f64>, exponent: Complex<f64>) -> Complex<f64> {
+    if base.im == 0.0 && exponent.im == 0.0 && (base.re >= 0.0 || exponent.re.fract() == 0.0) {
+        Complex::new(base.re.powf(exponent.re), 0.0)
+    } else if base == Complex::new(0.0, 0.0) {
+        base
+    } else {
+        base.powc(exponent)
+    }
+}
+
+/// Evaluate a syntax tree to a complex number.
+///
+/// The imaginary unit is available as `i` unless a variable of that name is set.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is evaluated
+/// * `context` - context providing variable values and user defined functions
+///
+/// # Returns
+///
+/// `Result<Complex<f64>, ExpressionError>` - value of the expression or evaluation error
+///
+pub fn evaluate_complex(
+    expression: &Expression,
+    context: &dyn EvaluationContext,
+) -> Result<Complex<f64>, ExpressionError> {
+    evaluate_complex_observed(expression, context, &mut |_, _| ())
+}
+
+/// Evaluated node of a syntax tree together with its complex value.
+pub type ComplexTraceStep<'a> = (&'a Expression, Complex<f64>);
+
+/// Evaluate a syntax tree to a complex number, recording the value of every evaluated node.
+///
+/// Nodes are recorded in evaluation order like in `trace`.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is evaluated
+/// * `context` - context providing variable values and user defined functions
+///
+/// # Returns
+///
+/// `Result<(Complex<f64>, Vec<(&Expression, Complex<f64>)>), ExpressionError>` - value of the
+///   expression and the evaluated nodes
+///
+pub fn trace_complex<'a>(
+    expression: &'a Expression,
+    context: &dyn EvaluationContext,
+) -> Result<(Complex<f64>, Vec<ComplexTraceStep<'a>>), ExpressionError> {
+    let mut steps = Vec::new();
+    let value = evaluate_complex_observed(expression, context, &mut |node, value| {
+        steps.push((node, value))
+    })?;
+    Ok((value, steps))
+}
+
+/// Evaluate a syntax tree to a complex number, calling `observe` with every evaluated node.
+fn evaluate_complex_observed<'a>(
+    expression: &'a Expression,
+    context: &dyn EvaluationContext,
+    observe: &mut dyn FnMut(&'a Expression, Complex<f64>),
+) -> Result<Complex<f64>, ExpressionError> {
+    let value = evaluate_complex_node(expression, context, observe)?;
+    observe(expression, value);
+    Ok(value)
+}
+
+/// Evaluate a single node of a syntax tree to a complex number.
+fn evaluate_complex_node<'a>(
+    expression: &'a Expression,
+    context: &dyn EvaluationContext,
+    observe: &mut dyn FnMut(&'a Expression, Complex<f64>),
+) -> Result<Complex<f64>, ExpressionError> {
+    match &expression.kind {
+        ExpressionKind::Number(value) => Ok(Complex::new(*value, 0.0)),
+        ExpressionKind::Variable(name) => match context.complex_variable(name) {
+            Some(value) => value,
+            None if name == "i" => Ok(Complex::new(0.0, 1.0)),
+            None => builtin_constant(name)
+                .map(Ok)
+                .or_else(|| context.resolve_variable(name))
+                .map(|value| value.map(|value| Complex::new(value, 0.0)))
+                .unwrap_or_else(|| Err(ExpressionError::VariableNotSet { name: name.clone() })),
+        },
+        ExpressionKind::Unary { op, operand } => {
+            let value = evaluate_complex_observed(operand, context, observe)?;
+            Ok(match op {
+                UnaryOperator::Plus => value,
+                UnaryOperator::Minus => -value,
+                UnaryOperator::Not => Complex::new(from_bool(value == Complex::new(0.0, 0.0)), 0.0),
+            })
+        }
+        ExpressionKind::Binary { op, lhs, rhs } => {
+            let lhs = evaluate_complex_observed(lhs, context, observe)?;
+            if let Some(value) = short_circuit(*op, from_bool(lhs != Complex::new(0.0, 0.0))) {
+                return Ok(Complex::new(value, 0.0));
+            }
+            let rhs = evaluate_complex_observed(rhs, context, observe)?;
+            match op {
+                BinaryOperator::Add => Ok(lhs + rhs),
+                BinaryOperator::Subtract => Ok(lhs - rhs),
+                BinaryOperator::Multiply => Ok(lhs * rhs),
+                BinaryOperator::Divide => {
+                    if rhs == Complex::new(0.0, 0.0) {
+                        Err(ExpressionError::DivisionByZero)
+                    } else {
+                        Ok(lhs / rhs)
+                    }
+                }
+                BinaryOperator::Power => Ok(complex_power(lhs, rhs)),
+                BinaryOperator::Equal => Ok(Complex::new(from_bool(lhs == rhs), 0.0)),
+                BinaryOperator::NotEqual => Ok(Complex::new(from_bool(lhs != rhs), 0.0)),
+                BinaryOperator::And | BinaryOperator::Or => {
+                    Ok(Complex::new(from_bool(rhs != Complex::new(0.0, 0.0)), 0.0))
+                }
+                _ if lhs.im == 0.0 && rhs.im == 0.0 => {
+                    apply_binary(*op, lhs.re, rhs.re).map(|value| Complex::new(value, 0.0))
+                }
+                _ => Err(ExpressionError::ComplexOperand {
+                    op: op.symbol().to_string(),
+                }),
+            }
+        }
+        ExpressionKind::Function { name, arguments } => {
+            check_arity(name, arguments.len(), context)?;
+            let values = arguments
+                .iter()
+                .map(|argument| evaluate_complex_observed(argument, context, observe))
+                .collect::<Result<Vec<Complex<f64>>, ExpressionError>>()?;
+            match context.call_function_complex(name, &values) {
+                Some(result) => result,
+                None => call_builtin_complex(name, &values),
+            }
+        }
+        ExpressionKind::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => {
+            let condition = evaluate_complex_observed(condition, context, observe)?;
+            if condition.im != 0.0 {
+                return Err(ExpressionError::ComplexOperand {
+                    op: "?:".to_string(),
+                });
+            }
+            if condition.re != 0.0 {
+                evaluate_complex_observed(then, context, observe)
+            } else {
+                evaluate_complex_observed(otherwise, context, observe)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Context with a fixed set of variables and no user defined functions.
+    struct Variables(HashMap<String, f64>);
+
+    impl EvaluationContext for Variables {
+        fn variable(&self, name: &str) -> Option<Result<f64, ExpressionError>> {
+            self.0.get(name).map(|value| Ok(*value))
+        }
+
+        fn function_arity(&self, _name: &str) -> Option<usize> {
+            None
+        }
+
+        fn call_function(
+            &self,
+            _name: &str,
+            _arguments: &[f64],
+        ) -> Option<Result<f64, ExpressionError>> {
+            None
+        }
+
+        fn call_function_complex(
+            &self,
+            _name: &str,
+            _arguments: &[Complex<f64>],
+        ) -> Option<Result<Complex<f64>, ExpressionError>> {
+            None
+        }
+    }
+
+    const VARIABLES: [(&str, f64); 4] = [("a", 1.5), ("b", 2.5), ("c", -0.5), ("d", 4.0)];
+
+    /// Expressions that qoqo_calculator accepts.
+    const CORPUS: [&str; 58] = [
+        "1+1",
+        "-2^2",
+        "-2**2",
+        "2^-2",
+        "-2^-2",
+        "2*-3^2",
+        "+a",
+        "-a+b",
+        "a*b-c/d",
+        "a/b/c",
+        "a-b-c",
+        "a - b * c + d",
+        "(a+b)*(c-d)",
+        "-(a+b)^2",
+        "(a)^(b)",
+        "a^(b+1)",
+        "a^b*c",
+        "c^d",
+        "1.5e3*.5",
+        "2E-3+1.74E-10",
+        "a # comment",
+        "sign(0)",
+        "sign(c)",
+        "sign(a)",
+        "sin(a)+cos(b)",
+        "tan(c)",
+        "asin(c)",
+        "acos(c)",
+        "atan(d)",
+        "sinh(a)",
+        "cosh(a)",
+        "tanh(a)",
+        "asinh(a)",
+        "acosh(b)",
+        "atanh(c)",
+        "arsinh(a)",
+        "arcosh(b)",
+        "artanh(c)",
+        "exp(a)",
+        "exp2(b)",
+        "expm1(0.1)",
+        "log(b)",
+        "log10(100)",
+        "sqrt(b)",
+        "cbrt(-8)",
+        "abs(c)",
+        "ceil(a)",
+        "floor(c)",
+        "fract(2.75)",
+        "round(2.5)",
+        "round(-2.5)",
+        "delta(0)+delta(a)",
+        "theta(0)+theta(c)+theta(a)",
+        "atan2(a,b)+hypot(a,b)",
+        "pow(d,c)",
+        "max(a,b)-min(c,-d)",
+        "sqrt(c)",
+        "log(0)",
+    ];
+
+    /// Evaluate an expression with qoqo_calculator and the expression module.
+    fn evaluate_both(input: &str) -> (f64, f64) {
+        let mut calculator = qoqo_calculator::Calculator::new();
+        for (name, value) in VARIABLES.iter() {
+            calculator.set_variable(name, *value);
+        }
+        let expected = calculator
+            .parse_str(input)
+            .unwrap_or_else(|err| panic!("qoqo_calculator rejects {}: {:?}", input, err));
+        let context = Variables(
+            VARIABLES
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+        );
+        let value = parse(input)
+            .and_then(|parsed| evaluate(&parsed, &context))
+            .unwrap_or_else(|err| panic!("{} fails: {}", input, err));
+        (expected, value)
+    }
+
+    #[test]
+    fn test_corpus_matches_qoqo_calculator() {
+        for input in CORPUS.iter() {
+            let (expected, value) = evaluate_both(input);
+            assert!(
+                value == expected || (value.is_nan() && expected.is_nan()),
+                "{}: qoqo_calculator {} != {}",
+                input,
+                expected,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_partial_results_match_qoqo_calculator() {
+        let known = Variables(
+            VARIABLES[..2]
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+        );
+        let extra = [
+            "-c^2 + a",
+            "-(c + a)^2",
+            "c * -a",
+            "c^a^2",
+            "2^-c * b",
+            "c * 1e-7 * a + d * 1e20 * b",
+            "-c - -a",
+        ];
+        for input in CORPUS.iter().chain(extra.iter()) {
+            let simplified = simplify(&parse(input).unwrap(), &known).unwrap();
+            check_portable(&simplified, &known)
+                .unwrap_or_else(|err| panic!("{} -> {}: {}", input, simplified, err));
+            let printed = simplified.to_string();
+            let (expected, value) = evaluate_both(&printed);
+            let original = evaluate(
+                &parse(input).unwrap(),
+                &Variables(
+                    VARIABLES
+                        .iter()
+                        .map(|(name, value)| (name.to_string(), *value))
+                        .collect(),
+                ),
+            )
+            .unwrap();
+            assert!(
+                (value == expected && value == original)
+                    || (value.is_nan() && expected.is_nan() && original.is_nan()),
+                "{} -> {}: qoqo_calculator {}, {}, original {}",
+                input,
+                printed,
+                expected,
+                value,
+                original
+            );
+        }
+        for input in [
+            "c > 0 ? 1 : 2",
+            "c && a",
+            "not c",
+            "factorial(c)",
+            "erf(c)",
+            "_c + a",
+        ]
+        .iter()
+        {
+            let simplified = simplify(&parse(input).unwrap(), &known).unwrap();
+            assert!(matches!(
+                check_portable(&simplified, &known),
+                Err(ExpressionError::Unportable { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_intentional_differences() {
+        let context = Variables(HashMap::new());
+        assert_eq!(evaluate(&parse("pi").unwrap(), &context).unwrap(), PI);
+        assert!(parse("2 3").is_err());
+        assert!(parse("2(3)").is_err());
+        assert_eq!(evaluate(&parse("2^3^2").unwrap(), &context).unwrap(), 512.0);
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let context = Variables(HashMap::new());
+        let nested = format!("{}1{}", "(".repeat(120), ")".repeat(120));
+        assert_eq!(evaluate(&parse(&nested).unwrap(), &context).unwrap(), 1.0);
+        for input in &[
+            format!("{}1{}", "(".repeat(1000), ")".repeat(1000)),
+            format!("{}1{}", "sin(".repeat(1000), ")".repeat(1000)),
+            format!("{}1", "-".repeat(100_000)),
+            format!("{}1", "not ".repeat(100_000)),
+            format!("1{}", "^1".repeat(100_000)),
+        ] {
+            assert!(matches!(
+                parse(input),
+                Err(ExpressionError::ParsingError { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright © 2020-2021 HQS Quantum Simulations GmbH. All Rights Reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+// in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the
+// License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+// express or implied. See the License for the specific language governing permissions and
+// limitations underthe License.
+
+//! expression_cache module
+//!
+//! Least recently used cache of parsed scripts keyed by their source string.
+
+use crate::expression::{self, ExpressionError, Statement};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Number of parsed scripts kept by default.
+pub const DEFAULT_CACHE_SIZE: usize = 256;
+
+/// Least recently used cache of parsed scripts.
+#[derive(Debug, Clone)]
+pub struct ExpressionCache {
+    /// Parsed statements with the time of their last use.
+    entries: HashMap<String, (Arc<Vec<Statement>>, u64)>,
+    /// Cached scripts ordered by the time of their last use.
+    recency: BTreeMap<u64, String>,
+    capacity: usize,
+    clock: u64,
+    hits: usize,
+    misses: usize,
+}
+
+impl ExpressionCache {
+    /// Create an empty cache holding at most `capacity` parsed scripts.
+    pub fn new(capacity: usize) -> Self {
+        ExpressionCache {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            capacity,
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Return the parsed statements of a script, parsing it only when it is not cached.
+    ///
+    /// Scripts that fail to parse are not cached.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - source string of the script
+    ///
+    /// # Returns
+    ///
+    /// `Result<Arc<Vec<Statement>>, ExpressionError>` - parsed statements or parsing error
+    ///
+    pub fn parse_script(&mut self, input: &str) -> Result<Arc<Vec<Statement>>, ExpressionError> {
+        self.clock += 1;
+        if let Some((statements, last_used)) = self.entries.get_mut(input) {
+            if let Some(key) = self.recency.remove(last_used) {
+                self.recency.insert(self.clock, key);
+            }
+            *last_used = self.clock;
+            self.hits += 1;
+            return Ok(statements.clone());
+        }
+        self.misses += 1;
+        let statements = Arc::new(expression::parse_script(input)?);
+        if self.capacity > 0 {
+            while self.entries.len() >= self.capacity {
+                self.evict_least_recent();
+            }
+            self.entries
+                .insert(input.to_string(), (statements.clone(), self.clock));
+            self.recency.insert(self.clock, input.to_string());
+        }
+        Ok(statements)
+    }
+
+    /// Change the maximal number of cached scripts, evicting the least recently used ones.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.evict_least_recent();
+        }
+    }
+
+    /// Return the maximal number of cached scripts.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Remove all cached scripts and reset the statistics.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.hits = 0;
+        self.misses = 0;
+    }
+
+    /// Return the number of hits, the number of misses and the number of cached scripts.
+    pub fn statistics(&self) -> (usize, usize, usize) {
+        (self.hits, self.misses, self.entries.len())
+    }
+
+    /// Remove the least recently used script.
+    fn evict_least_recent(&mut self) {
+        if let Some(&oldest) = self.recency.keys().next() {
+            if let Some(input) = self.recency.remove(&oldest) {
+                self.entries.remove(&input);
+            }
+        }
+    }
+}
@@ -15,26 +15,98 @@
 //! qoqo_calculator_pyo3 module bringing the qoqo_calculator rust library to Python.
 
 use pyo3::prelude::*;
+use pyo3::types::PyDict;
 use pyo3::wrap_pyfunction;
 mod calculator_float;
-pub use calculator_float::CalculatorFloatWrapper;
 pub use calculator_float::convert_into_calculator_float;
+pub use calculator_float::CalculatorFloatWrapper;
 mod calculator_complex;
-pub use calculator_complex::CalculatorComplexWrapper;
 pub use calculator_complex::convert_into_calculator_complex;
+pub use calculator_complex::CalculatorComplexWrapper;
 mod calculator;
+mod constants;
+mod distribution;
+mod expression;
+mod expression_cache;
+mod precision;
+mod shared_calculator;
+mod uncertainty;
+mod variable_file;
 pub use calculator::parse_str;
+pub use calculator::parse_str_partial;
+pub use calculator::parse_str_with_variables;
+pub use calculator::validate_str;
+pub use calculator::CalculatorScopeWrapper;
 pub use calculator::CalculatorWrapper;
+pub use shared_calculator::SharedCalculatorWrapper;
+
+/// Parse a string expression to float.
+///
+/// # Arguments
+///
+/// * `expression` - Expression that is parsed
+/// * `variables` - Optional mapping or iterable of (name, value) pairs
+/// * `kwargs` - Variables given as keyword arguments
+///
+/// # Returns
+///
+/// `PyResult<f64>` - value of the expression, ValueError when a variable is not set
+///
+#[pyfunction(variables = "None", kwargs = "**")]
+fn parse_string(
+    expression: &str,
+    variables: Option<&PyAny>,
+    kwargs: Option<&PyDict>,
+) -> PyResult<f64> {
+    parse_str_with_variables(expression, variables, kwargs)
+}
 
+/// Parse a string expression to CalculatorFloat, keeping variables that are not set.
+///
+/// # Arguments
+///
+/// * `expression` - Expression that is parsed
+/// * `variables` - Optional mapping or iterable of (name, value) pairs
+/// * `kwargs` - Variables given as keyword arguments
+///
+/// # Returns
+///
+/// `PyResult<CalculatorFloatWrapper>` - CalculatorFloat containing the value of the expression
+///                                      or the expression with the set variables substituted
+///
+#[pyfunction(variables = "None", kwargs = "**")]
+fn parse_string_partial(
+    expression: &str,
+    variables: Option<&PyAny>,
+    kwargs: Option<&PyDict>,
+) -> PyResult<CalculatorFloatWrapper> {
+    Ok(CalculatorFloatWrapper {
+        cf_internal: parse_str_partial(expression, variables, kwargs)?,
+    })
+}
+
+/// Check a string expression without evaluating it.
+///
+/// # Arguments
+///
+/// * `expression` - Expression that is checked
+///
+/// # Returns
+///
+/// `PyResult<PyObject>` - report of the referenced names, see `Calculator.validate`,
+///                        ValueError with the `position` of syntax errors
+///
 #[pyfunction]
-fn parse_string(expression: &str) -> PyResult<f64> {
-    parse_str(expression)
+fn validate_string(expression: &str) -> PyResult<PyObject> {
+    validate_str(expression)
 }
 
 /// qoqo_calculator_pyo3 module bringing the qoqo_calculator rust library to Python.
 ///
 /// qoqo_calculator is a rust library implementing:
 /// * Calculator: a struct for parsing string expressions to floats
+/// * SharedCalculator: a Calculator that can be shared between threads and
+///                     evaluates expressions with the GIL released
 /// * CalculatorFloat: a type that contains a float or a symbolic math
 ///                    expression in string form.
 /// * CalculatorComplex: a type that contains a CalculatorFloat as the real part
@@ -45,8 +117,22 @@ fn parse_string(expression: &str) -> PyResult<f64> {
 #[pymodule]
 fn qoqo_calculator_pyo3(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<CalculatorWrapper>()?;
+    // pyo3 does not fill the mapping slots for __setitem__ and __delitem__ defined in
+    // #[pymethods], and the PyMappingProtocol versions keep the Calculator mutably borrowed
+    // while the observers run, so observers could not read it. Setting the attributes on the
+    // type again makes Python fill the slots with the methods, see CalculatorWrapper.__setitem__
+    let calculator = m.getattr("Calculator")?;
+    for name in ["__setitem__", "__delitem__"].iter() {
+        calculator.setattr(name, calculator.getattr(name)?)?;
+    }
+    m.add_class::<CalculatorScopeWrapper>()?;
+    m.add_class::<SharedCalculatorWrapper>()?;
     m.add_class::<CalculatorFloatWrapper>()?;
     m.add_class::<CalculatorComplexWrapper>()?;
     m.add_function(wrap_pyfunction!(parse_string, m)?).unwrap();
+    m.add_function(wrap_pyfunction!(parse_string_partial, m)?)
+        .unwrap();
+    m.add_function(wrap_pyfunction!(validate_string, m)?)
+        .unwrap();
     Ok(())
 }
@@ -0,0 +1,1252 @@
+// Copyright © 2020-2021 HQS Quantum Simulations GmbH. All Rights Reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+// in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the
+// License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+// express or implied. See the License for the specific language governing permissions and
+// limitations underthe License.
+
+//! precision module
+//!
+//! Decimal floating point numbers with a configurable number of significant digits
+//! and the evaluation of expression syntax trees with them.
+
+use crate::expression::{
+    BinaryOperator, EvaluationContext, Expression, ExpressionError, ExpressionKind, UnaryOperator,
+};
+use num_bigint::{BigInt, Sign};
+use num_traits::{One, Signed, ToPrimitive, Zero};
+use std::cmp::Ordering;
+
+/// Number of extra digits carried in intermediate results of an evaluation.
+const GUARD_DIGITS: u64 = 10;
+
+/// Largest decimal exponent of the values of an evaluation.
+///
+/// Bounds the number of digits needed for argument reduction and exact rounding,
+/// the range of floats is much smaller.
+const MAX_ORDER: i64 = 10_000;
+
+/// Decimal floating point number with the value `mantissa * 10^exponent`.
+#[derive(Debug, Clone)]
+pub struct Decimal {
+    mantissa: BigInt,
+    exponent: i64,
+}
+
+/// Return 10 to the power of `n`.
+fn power_of_ten(n: u64) -> BigInt {
+    num_traits::pow(BigInt::from(10), n as usize)
+}
+
+/// Return the number of decimal digits of an integer.
+fn digit_count(value: &BigInt) -> u64 {
+    value.magnitude().to_string().len() as u64
+}
+
+/// Divide two integers, rounding half away from zero.
+fn divide_rounded(dividend: &BigInt, divisor: &BigInt) -> BigInt {
+    let quotient = dividend / divisor;
+    let remainder = dividend % divisor;
+    if remainder.abs() * 2 >= divisor.abs() {
+        quotient + remainder.signum() * divisor.signum()
+    } else {
+        quotient
+    }
+}
+
+/// Create the error returned for values and operations that can not be evaluated.
+fn precision_error(msg: String) -> ExpressionError {
+    ExpressionError::PrecisionError { msg }
+}
+
+impl Decimal {
+    /// Return the number zero.
+    pub fn zero() -> Decimal {
+        Decimal {
+            mantissa: BigInt::zero(),
+            exponent: 0,
+        }
+    }
+
+    /// Return the number one.
+    pub fn one() -> Decimal {
+        Decimal::from_integer(1)
+    }
+
+    /// Convert an integer to a Decimal.
+    pub fn from_integer(value: i64) -> Decimal {
+        Decimal {
+            mantissa: BigInt::from(value),
+            exponent: 0,
+        }
+    }
+
+    /// Convert a truth value to a Decimal, 1 for true and 0 for false.
+    fn from_bool(value: bool) -> Decimal {
+        Decimal::from_integer(value as i64)
+    }
+
+    /// Convert a float to the Decimal with the shortest representation that rounds to it.
+    ///
+    /// Floats set from decimal numbers keep their decimal value, `0.1` is converted to 0.1
+    /// and not to the binary approximation of 0.1 stored in the float.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - the float that is converted
+    ///
+    /// # Returns
+    ///
+    /// `Option<Decimal>` - decimal value or None when the float is not finite
+    ///
+    pub fn from_f64(value: f64) -> Option<Decimal> {
+        if !value.is_finite() {
+            return None;
+        }
+        let magnitude = Decimal::parse(&format!("{:e}", value.abs()))?;
+        if value < 0.0 {
+            Some(magnitude.neg())
+        } else {
+            Some(magnitude)
+        }
+    }
+
+    /// Parse an unsigned decimal literal like `12.5e-3` without rounding.
+    ///
+    /// # Arguments
+    ///
+    /// * `literal` - the literal that is parsed
+    ///
+    /// # Returns
+    ///
+    /// `Option<Decimal>` - exact decimal value or None when the literal is not valid
+    ///
+    pub fn parse(literal: &str) -> Option<Decimal> {
+        let (number, exponent) = match literal.find(&['e', 'E'][..]) {
+            Some(position) => (
+                &literal[..position],
+                literal[position + 1..].parse::<i64>().ok()?,
+            ),
+            None => (literal, 0),
+        };
+        let (integer, fraction) = match number.find('.') {
+            Some(position) => (&number[..position], &number[position + 1..]),
+            None => (number, ""),
+        };
+        let digits = format!("{}{}", integer, fraction);
+        if digits.is_empty() || !digits.bytes().all(|digit| digit.is_ascii_digit()) {
+            return None;
+        }
+        Some(Decimal {
+            mantissa: digits.parse().ok()?,
+            exponent: exponent.checked_sub(fraction.len() as i64)?,
+        })
+    }
+
+    /// Convert the Decimal to the nearest float.
+    pub fn to_f64(&self) -> f64 {
+        format!("{}e{}", self.mantissa, self.exponent)
+            .parse()
+            .unwrap_or(f64::NAN)
+    }
+
+    /// Format the Decimal as a decimal string with at most `digits` significant digits.
+    ///
+    /// Trailing zeros are removed. Very large and very small numbers are written
+    /// in scientific notation.
+    ///
+    /// # Arguments
+    ///
+    /// * `digits` - number of significant digits
+    ///
+    /// # Returns
+    ///
+    /// `String` - decimal representation of the number
+    ///
+    pub fn to_decimal_string(&self, digits: usize) -> String {
+        let rounded = self.round(digits as u64);
+        if rounded.is_zero() {
+            return "0".to_string();
+        }
+        let sign = if rounded.signum() < 0 { "-" } else { "" };
+        let mut text = rounded.mantissa.magnitude().to_string();
+        let mut exponent = rounded.exponent;
+        while text.len() > 1 && text.ends_with('0') {
+            text.pop();
+            exponent += 1;
+        }
+        let order = exponent + text.len() as i64 - 1;
+        if order < -6 || order >= digits.max(16) as i64 {
+            let (first, rest) = text.split_at(1);
+            if rest.is_empty() {
+                format!("{}{}e{}", sign, first, order)
+            } else {
+                format!("{}{}.{}e{}", sign, first, rest, order)
+            }
+        } else if exponent >= 0 {
+            format!("{}{}{}", sign, text, "0".repeat(exponent as usize))
+        } else {
+            let point = text.len() as i64 + exponent;
+            if point > 0 {
+                let (integer, fraction) = text.split_at(point as usize);
+                format!("{}{}.{}", sign, integer, fraction)
+            } else {
+                format!("{}0.{}{}", sign, "0".repeat((-point) as usize), text)
+            }
+        }
+    }
+
+    /// Return true when the number is zero.
+    pub fn is_zero(&self) -> bool {
+        self.mantissa.is_zero()
+    }
+
+    /// Return -1, 0 or 1 according to the sign of the number.
+    fn signum(&self) -> i32 {
+        match self.mantissa.sign() {
+            Sign::Minus => -1,
+            Sign::NoSign => 0,
+            Sign::Plus => 1,
+        }
+    }
+
+    /// Return the decimal exponent of the leading digit, a very small number for zero.
+    fn order(&self) -> i64 {
+        if self.is_zero() {
+            i64::MIN / 4
+        } else {
+            self.exponent + digit_count(&self.mantissa) as i64 - 1
+        }
+    }
+
+    /// Round to at most `digits` significant digits, rounding half away from zero.
+    fn round(&self, digits: u64) -> Decimal {
+        let count = digit_count(&self.mantissa);
+        if count <= digits {
+            return self.clone();
+        }
+        let dropped = count - digits;
+        Decimal {
+            mantissa: divide_rounded(&self.mantissa, &power_of_ten(dropped)),
+            exponent: self.exponent + dropped as i64,
+        }
+    }
+
+    /// Return the negated number.
+    fn neg(&self) -> Decimal {
+        Decimal {
+            mantissa: -&self.mantissa,
+            exponent: self.exponent,
+        }
+    }
+
+    /// Return the absolute value.
+    fn abs(&self) -> Decimal {
+        Decimal {
+            mantissa: self.mantissa.abs(),
+            exponent: self.exponent,
+        }
+    }
+
+    /// Add two numbers, rounding the result to `digits` significant digits.
+    fn add(&self, other: &Decimal, digits: u64) -> Decimal {
+        if self.is_zero() {
+            return other.round(digits);
+        }
+        if other.is_zero() {
+            return self.round(digits);
+        }
+        let (high, low) = if self.exponent >= other.exponent {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        // Skip aligning operands that are too small to change the rounded result
+        if high.order() - low.order() > digits as i64 + 1 {
+            return high.round(digits);
+        }
+        let shift = (high.exponent - low.exponent) as u64;
+        Decimal {
+            mantissa: &high.mantissa * power_of_ten(shift) + &low.mantissa,
+            exponent: low.exponent,
+        }
+        .round(digits)
+    }
+
+    /// Subtract two numbers, rounding the result to `digits` significant digits.
+    fn sub(&self, other: &Decimal, digits: u64) -> Decimal {
+        self.add(&other.neg(), digits)
+    }
+
+    /// Multiply two numbers, rounding the result to `digits` significant digits.
+    fn mul(&self, other: &Decimal, digits: u64) -> Decimal {
+        Decimal {
+            mantissa: &self.mantissa * &other.mantissa,
+            exponent: self.exponent + other.exponent,
+        }
+        .round(digits)
+    }
+
+    /// Divide two numbers, rounding the result to `digits` significant digits.
+    fn div(&self, other: &Decimal, digits: u64) -> Result<Decimal, ExpressionError> {
+        if other.is_zero() {
+            Err(ExpressionError::DivisionByZero)
+        } else {
+            Ok(self.quotient(other, digits))
+        }
+    }
+
+    /// Divide by a number that is known to be nonzero.
+    fn quotient(&self, other: &Decimal, digits: u64) -> Decimal {
+        let shift = (digits as i64 + 2 + digit_count(&other.mantissa) as i64
+            - digit_count(&self.mantissa) as i64)
+            .max(0) as u64;
+        Decimal {
+            mantissa: &self.mantissa * power_of_ten(shift) / &other.mantissa,
+            exponent: self.exponent - other.exponent - shift as i64,
+        }
+        .round(digits)
+    }
+
+    /// Divide by a nonzero integer.
+    fn div_integer(&self, divisor: i64, digits: u64) -> Decimal {
+        self.quotient(&Decimal::from_integer(divisor), digits)
+    }
+
+    /// Compare two numbers exactly.
+    fn compare(&self, other: &Decimal) -> Ordering {
+        let (sign, other_sign) = (self.signum(), other.signum());
+        if sign != other_sign || sign == 0 {
+            return sign.cmp(&other_sign);
+        }
+        let magnitude = match self.order().cmp(&other.order()) {
+            Ordering::Equal => {
+                let exponent = self.exponent.min(other.exponent);
+                let lhs = self.mantissa.abs() * power_of_ten((self.exponent - exponent) as u64);
+                let rhs = other.mantissa.abs() * power_of_ten((other.exponent - exponent) as u64);
+                lhs.cmp(&rhs)
+            }
+            ordering => ordering,
+        };
+        if sign > 0 {
+            magnitude
+        } else {
+            magnitude.reverse()
+        }
+    }
+
+    /// Return true when the number is an integer.
+    fn is_integer(&self) -> bool {
+        if self.exponent >= 0 || self.is_zero() {
+            return true;
+        }
+        // Nonzero numbers below one are not integers
+        self.order() >= 0 && (&self.mantissa % power_of_ten((-self.exponent) as u64)).is_zero()
+    }
+
+    /// Return the value as i64 when the number is an integer of moderate size.
+    fn to_small_integer(&self) -> Option<i64> {
+        if self.is_zero() {
+            return Some(0);
+        }
+        if self.order() > 9 || !self.is_integer() {
+            return None;
+        }
+        self.trunc().mantissa.to_i64()
+    }
+
+    /// Round towards zero to an integer.
+    fn trunc(&self) -> Decimal {
+        if self.exponent >= 0 {
+            return self.clone();
+        }
+        if self.order() < 0 {
+            return Decimal::zero();
+        }
+        Decimal {
+            mantissa: &self.mantissa / power_of_ten((-self.exponent) as u64),
+            exponent: 0,
+        }
+    }
+
+    /// Round to the nearest integer, rounding half away from zero.
+    fn round_integer(&self) -> Decimal {
+        if self.exponent >= 0 {
+            return self.clone();
+        }
+        // Numbers below 0.1 round to zero
+        if self.order() < -1 {
+            return Decimal::zero();
+        }
+        Decimal {
+            mantissa: divide_rounded(&self.mantissa, &power_of_ten((-self.exponent) as u64)),
+            exponent: 0,
+        }
+    }
+
+    /// Round towards negative infinity to an integer.
+    fn floor(&self) -> Decimal {
+        let truncated = self.trunc();
+        if self.signum() < 0 && !self.is_integer() {
+            Decimal {
+                mantissa: truncated.mantissa - 1,
+                exponent: 0,
+            }
+        } else {
+            truncated
+        }
+    }
+
+    /// Round towards positive infinity to an integer.
+    fn ceil(&self) -> Decimal {
+        let truncated = self.trunc();
+        if self.signum() > 0 && !self.is_integer() {
+            Decimal {
+                mantissa: truncated.mantissa + 1,
+                exponent: 0,
+            }
+        } else {
+            truncated
+        }
+    }
+
+    /// Return a float estimate of the natural logarithm of a positive number.
+    ///
+    /// Unlike `self.to_f64().ln()` the estimate does not overflow for huge exponents.
+    fn ln_estimate(&self) -> f64 {
+        let text = self.mantissa.magnitude().to_string();
+        let leading = text.len().min(17);
+        let value: f64 = text[..leading].parse().unwrap_or(1.0);
+        value.ln()
+            + (self.exponent + (text.len() - leading) as i64) as f64 * std::f64::consts::LN_10
+    }
+}
+
+/// Return true when adding `term` to `sum` no longer changes `sum` at the given number of digits.
+fn negligible(term: &Decimal, sum: &Decimal, digits: u64) -> bool {
+    term.is_zero() || (!sum.is_zero() && term.order() < sum.order() - digits as i64 - 1)
+}
+
+/// Return the number of extra digits needed for arguments close to zero.
+fn small_argument_digits(x: &Decimal) -> u64 {
+    if x.is_zero() {
+        0
+    } else {
+        (-x.order()).max(0) as u64
+    }
+}
+
+/// Return pi using Machin's formula pi = 16 atan(1/5) - 4 atan(1/239).
+fn pi(digits: u64) -> Decimal {
+    let working = digits + 4;
+    let first = arctan_reciprocal(5, working).mul(&Decimal::from_integer(16), working);
+    let second = arctan_reciprocal(239, working).mul(&Decimal::from_integer(4), working);
+    first.sub(&second, working).round(digits)
+}
+
+/// Return atan(1/n) for an integer n > 1 using its Taylor series.
+fn arctan_reciprocal(n: i64, digits: u64) -> Decimal {
+    let square = n * n;
+    let mut power = Decimal::one().div_integer(n, digits);
+    let mut sum = power.clone();
+    let mut k = 1;
+    loop {
+        power = power.div_integer(square, digits);
+        let term = power.div_integer(2 * k + 1, digits);
+        if negligible(&term, &sum, digits) {
+            return sum;
+        }
+        sum = if k % 2 == 1 {
+            sum.sub(&term, digits)
+        } else {
+            sum.add(&term, digits)
+        };
+        k += 1;
+    }
+}
+
+/// Return the n-th root of a non-negative number.
+fn root(x: &Decimal, n: u32, digits: u64) -> Decimal {
+    if x.is_zero() {
+        return Decimal::zero();
+    }
+    // Scale the mantissa so that the integer root has enough digits and the exponent is divisible by n
+    let mut scale = ((digits + 2) * n as u64).saturating_sub(digit_count(&x.mantissa));
+    while (x.exponent - scale as i64).rem_euclid(n as i64) != 0 {
+        scale += 1;
+    }
+    let mantissa = &x.mantissa * power_of_ten(scale);
+    Decimal {
+        mantissa: mantissa.nth_root(n),
+        exponent: (x.exponent - scale as i64) / n as i64,
+    }
+    .round(digits)
+}
+
+/// Return the square root of a non-negative number.
+fn sqrt(x: &Decimal, digits: u64) -> Result<Decimal, ExpressionError> {
+    if x.signum() < 0 {
+        Err(precision_error(
+            "Square root of a negative number is not real".to_string(),
+        ))
+    } else {
+        Ok(root(x, 2, digits))
+    }
+}
+
+/// Return the exponential function using argument halving and its Taylor series.
+fn exp(x: &Decimal, digits: u64) -> Result<Decimal, ExpressionError> {
+    if x.is_zero() {
+        return Ok(Decimal::one());
+    }
+    if x.order() > 15 {
+        return Err(precision_error(format!(
+            "Argument {} of exp is too large",
+            x.to_decimal_string(17)
+        )));
+    }
+    // |x| < 10^(order + 1) < 2^(4 (order + 1)), so the reduced argument is below 2^-8
+    let halvings = 4 * (x.order() + 1).max(0) as u64 + 8;
+    let working = digits + halvings / 3 + 2;
+    let reduced = x.quotient(
+        &Decimal {
+            mantissa: BigInt::one() << halvings as usize,
+            exponent: 0,
+        },
+        working,
+    );
+    let mut sum = Decimal::one();
+    let mut term = Decimal::one();
+    let mut n = 1;
+    loop {
+        term = term.mul(&reduced, working).div_integer(n, working);
+        if negligible(&term, &sum, working) {
+            break;
+        }
+        sum = sum.add(&term, working);
+        n += 1;
+    }
+    for _ in 0..halvings {
+        sum = sum.mul(&sum, working);
+    }
+    Ok(sum.round(digits))
+}
+
+/// Return the natural logarithm using Halley's iteration for exp(y) = x.
+fn ln(x: &Decimal, digits: u64) -> Result<Decimal, ExpressionError> {
+    if x.signum() <= 0 {
+        return Err(precision_error(
+            "Logarithm of a non-positive number is not real".to_string(),
+        ));
+    }
+    let working = digits + 4;
+    let mut y = Decimal::from_f64(x.ln_estimate()).unwrap_or_else(Decimal::zero);
+    let two = Decimal::from_integer(2);
+    for _ in 0..64 {
+        let exp_y = exp(&y, working)?;
+        let correction = x
+            .sub(&exp_y, working)
+            .mul(&two, working)
+            .quotient(&x.add(&exp_y, working), working);
+        y = y.add(&correction, working);
+        if negligible(&correction, &y, working) {
+            break;
+        }
+    }
+    Ok(y.round(digits))
+}
+
+/// Return the logarithm of x to an integer base.
+fn log_base(x: &Decimal, base: i64, digits: u64) -> Result<Decimal, ExpressionError> {
+    let working = digits + 4;
+    let numerator = ln(x, working)?;
+    let denominator = ln(&Decimal::from_integer(base), working)?;
+    Ok(numerator.quotient(&denominator, working).round(digits))
+}
+
+/// Return the sine and cosine after reducing the argument to [-pi, pi].
+fn sin_cos(x: &Decimal, digits: u64) -> (Decimal, Decimal) {
+    let working = digits + 4 + x.order().max(0) as u64;
+    let two_pi = pi(working).mul(&Decimal::from_integer(2), working);
+    let turns = x.quotient(&two_pi, working).round_integer();
+    let reduced = x.sub(&turns.mul(&two_pi, u64::MAX), working);
+    let square = reduced.mul(&reduced, working);
+    let mut sin = reduced.clone();
+    let mut term = reduced;
+    let mut n = 1;
+    loop {
+        term = term
+            .mul(&square, working)
+            .div_integer((2 * n) * (2 * n + 1), working)
+            .neg();
+        if negligible(&term, &sin, working) {
+            break;
+        }
+        sin = sin.add(&term, working);
+        n += 1;
+    }
+    let mut cos = Decimal::one();
+    let mut term = Decimal::one();
+    let mut n = 1;
+    loop {
+        term = term
+            .mul(&square, working)
+            .div_integer((2 * n - 1) * (2 * n), working)
+            .neg();
+        if negligible(&term, &cos, working) {
+            break;
+        }
+        cos = cos.add(&term, working);
+        n += 1;
+    }
+    (sin.round(digits), cos.round(digits))
+}
+
+/// Return the arc tangent using argument reduction and its Taylor series.
+fn atan(x: &Decimal, digits: u64) -> Decimal {
+    if x.is_zero() {
+        return Decimal::zero();
+    }
+    let working = digits + 4;
+    let one = Decimal::one();
+    if x.abs().compare(&one) == Ordering::Greater {
+        // atan(x) = sign(x) pi / 2 - atan(1 / x)
+        let half_pi = pi(working).div_integer(2, working);
+        let inner = atan(&one.quotient(x, working), working);
+        let outer = if x.signum() > 0 {
+            half_pi
+        } else {
+            half_pi.neg()
+        };
+        return outer.sub(&inner, working).round(digits);
+    }
+    // atan(x) = 2 atan(x / (1 + sqrt(1 + x^2))) until the series converges quickly
+    let limit = Decimal {
+        mantissa: BigInt::one(),
+        exponent: -1,
+    };
+    let mut reduced = x.clone();
+    let mut doublings = 0;
+    while reduced.abs().compare(&limit) == Ordering::Greater {
+        let square = one.add(&reduced.mul(&reduced, working), working);
+        let denominator = one.add(&root(&square, 2, working), working);
+        reduced = reduced.quotient(&denominator, working);
+        doublings += 1;
+    }
+    let square = reduced.mul(&reduced, working);
+    let mut sum = reduced.clone();
+    let mut power = reduced;
+    let mut k = 1;
+    loop {
+        power = power.mul(&square, working).neg();
+        let term = power.div_integer(2 * k + 1, working);
+        if negligible(&term, &sum, working) {
+            break;
+        }
+        sum = sum.add(&term, working);
+        k += 1;
+    }
+    let factor = Decimal::from_integer(1 << doublings);
+    sum.mul(&factor, working).round(digits)
+}
+
+/// Return the arc sine.
+fn asin(x: &Decimal, digits: u64) -> Result<Decimal, ExpressionError> {
+    let working = digits + 4;
+    let one = Decimal::one();
+    match x.abs().compare(&one) {
+        Ordering::Greater => Err(precision_error(
+            "Argument of asin is outside of [-1, 1]".to_string(),
+        )),
+        Ordering::Equal => {
+            let half_pi = pi(working).div_integer(2, working);
+            Ok(if x.signum() > 0 {
+                half_pi
+            } else {
+                half_pi.neg()
+            }
+            .round(digits))
+        }
+        Ordering::Less => {
+            let cosine = root(&one.sub(&x.mul(x, working), working), 2, working);
+            Ok(atan(&x.quotient(&cosine, working), working).round(digits))
+        }
+    }
+}
+
+/// Return the arc tangent of y / x in the quadrant of the point (x, y).
+fn atan2(y: &Decimal, x: &Decimal, digits: u64) -> Decimal {
+    let working = digits + 4;
+    if x.is_zero() {
+        let half_pi = pi(working).div_integer(2, working);
+        return half_pi
+            .mul(&Decimal::from_integer(y.signum() as i64), working)
+            .round(digits);
+    }
+    let angle = atan(&y.quotient(x, working), working);
+    if x.signum() > 0 {
+        angle.round(digits)
+    } else if y.signum() < 0 {
+        angle.sub(&pi(working), working).round(digits)
+    } else {
+        angle.add(&pi(working), working).round(digits)
+    }
+}
+
+/// Return the power of two numbers.
+///
+/// Integer exponents are evaluated by repeated squaring, other exponents as exp(y ln(x)).
+fn pow(base: &Decimal, exponent: &Decimal, digits: u64) -> Result<Decimal, ExpressionError> {
+    if let Some(n) = exponent.to_small_integer() {
+        let working = digits + 4 + n.unsigned_abs().to_string().len() as u64;
+        let mut result = Decimal::one();
+        let mut square = base.clone();
+        let mut remaining = n.unsigned_abs();
+        while remaining > 0 {
+            if remaining & 1 == 1 {
+                result = result.mul(&square, working);
+            }
+            remaining >>= 1;
+            if remaining > 0 {
+                square = square.mul(&square, working);
+            }
+        }
+        return if n < 0 {
+            Ok(Decimal::one().div(&result, working)?.round(digits))
+        } else {
+            Ok(result.round(digits))
+        };
+    }
+    match base.signum() {
+        0 if exponent.signum() > 0 => Ok(Decimal::zero()),
+        0 => Err(ExpressionError::DivisionByZero),
+        sign if sign < 0 => Err(precision_error(
+            "Power of a negative number with a non-integer exponent is not real".to_string(),
+        )),
+        _ => {
+            // The error of ln(x) is amplified by the size of y ln(x)
+            let magnitude = base.ln_estimate().abs() * exponent.to_f64().abs();
+            let working = digits + 4 + magnitude.log10().clamp(0.0, 1e6) as u64;
+            let logarithm = ln(base, working)?;
+            exp(&exponent.mul(&logarithm, working), digits)
+        }
+    }
+}
+
+/// Evaluate a built-in function.
+fn call_builtin(
+    name: &str,
+    arguments: &[Decimal],
+    digits: u64,
+) -> Option<Result<Decimal, ExpressionError>> {
+    let x = arguments.first()?;
+    let one = Decimal::one();
+    let small = digits + small_argument_digits(x);
+    let value = match name {
+        "sin" => Ok(sin_cos(x, digits).0),
+        "cos" => Ok(sin_cos(x, digits).1),
+        "tan" => {
+            let (sin, cos) = sin_cos(x, digits + 4);
+            sin.div(&cos, digits)
+        }
+        "asin" => asin(x, digits),
+        "acos" => asin(x, digits + 4).map(|angle| {
+            pi(digits + 4)
+                .div_integer(2, digits + 4)
+                .sub(&angle, digits)
+        }),
+        "atan" => Ok(atan(x, digits)),
+        "sinh" | "cosh" | "tanh" => exp(x, small + 4).and_then(|exp_x| {
+            let exp_minus_x = one.quotient(&exp_x, small + 4);
+            let difference = exp_x.sub(&exp_minus_x, small + 4);
+            let sum = exp_x.add(&exp_minus_x, small + 4);
+            match name {
+                "sinh" => Ok(difference.div_integer(2, digits)),
+                "cosh" => Ok(sum.div_integer(2, digits)),
+                _ => difference.div(&sum, digits),
+            }
+        }),
+        "asinh" | "arsinh" => {
+            let working = small + 4;
+            let magnitude = x.abs();
+            let square = magnitude.mul(&magnitude, working).add(&one, working);
+            ln(&magnitude.add(&root(&square, 2, working), working), working).map(|value| {
+                value
+                    .mul(&Decimal::from_integer(x.signum() as i64), working)
+                    .round(digits)
+            })
+        }
+        "acosh" | "arcosh" => {
+            if x.compare(&one) == Ordering::Less {
+                Err(precision_error(
+                    "Argument of acosh is smaller than 1".to_string(),
+                ))
+            } else {
+                let working = digits + 4;
+                let square = x.mul(x, working).sub(&one, working);
+                ln(&x.add(&root(&square, 2, working), working), digits)
+            }
+        }
+        "atanh" | "artanh" => {
+            if x.abs().compare(&one) != Ordering::Less {
+                Err(precision_error(
+                    "Argument of atanh is outside of (-1, 1)".to_string(),
+                ))
+            } else {
+                let working = small + 4;
+                let ratio = one.add(x, working).quotient(&one.sub(x, working), working);
+                ln(&ratio, working).map(|value| value.div_integer(2, digits))
+            }
+        }
+        "exp" => exp(x, digits),
+        "exp2" => ln(&Decimal::from_integer(2), digits + 4)
+            .and_then(|ln_2| exp(&x.mul(&ln_2, digits + 4), digits)),
+        "expm1" => exp(x, small + 4).map(|value| value.sub(&one, digits)),
+        "log" | "ln" => ln(x, digits),
+        "log10" => log_base(x, 10, digits),
+        "log2" => log_base(x, 2, digits),
+        "log1p" => ln(&one.add(x, small + 4), digits),
+        "sqrt" => sqrt(x, digits),
+        "cbrt" => {
+            Ok(root(&x.abs(), 3, digits).mul(&Decimal::from_integer(x.signum() as i64), digits))
+        }
+        "abs" => Ok(x.abs()),
+        "sign" | "signum" => Ok(Decimal::from_integer(if x.signum() < 0 { -1 } else { 1 })),
+        "floor" => Ok(x.floor()),
+        "ceil" => Ok(x.ceil()),
+        "round" => Ok(x.round_integer()),
+        "trunc" => Ok(x.trunc()),
+        "fract" => Ok(x.sub(&x.trunc(), digits)),
+        "recip" => one.div(x, digits),
+        "conj" | "real" => Ok(x.clone()),
+        "imag" => Ok(Decimal::zero()),
+        "arg" => Ok(if x.signum() < 0 {
+            pi(digits)
+        } else {
+            Decimal::zero()
+        }),
+        "erf" | "tgamma" | "lgamma" | "factorial" => Err(precision_error(format!(
+            "Function {} can not be evaluated in high precision",
+            name
+        ))),
+        "delta" | "theta" => {
+            // Same tolerance as the float evaluation
+            let tolerance = Decimal::from_f64(f64::EPSILON)?;
+            Ok(if x.abs().compare(&tolerance) == Ordering::Less {
+                match name {
+                    "delta" => Decimal::one(),
+                    _ => Decimal {
+                        mantissa: BigInt::from(5),
+                        exponent: -1,
+                    },
+                }
+            } else if name == "delta" || x.signum() < 0 {
+                Decimal::zero()
+            } else {
+                one
+            })
+        }
+        "parity" => {
+            let half = Decimal {
+                mantissa: BigInt::from(5),
+                exponent: -1,
+            };
+            // Enough digits to add one half exactly
+            let working = x.order().max(0) as u64 + (-x.exponent).max(0) as u64 + 2;
+            let nearest = x.add(&half, working).floor();
+            let odd = nearest.exponent == 0 && !(&nearest.mantissa % BigInt::from(2)).is_zero();
+            Ok(Decimal::from_integer(if odd { -1 } else { 1 }))
+        }
+        "atan2" => Ok(atan2(x, arguments.get(1)?, digits)),
+        "hypot" => {
+            let y = arguments.get(1)?;
+            let working = digits + 4;
+            let square = x.mul(x, working).add(&y.mul(y, working), working);
+            Ok(root(&square, 2, digits))
+        }
+        "pow" => pow(x, arguments.get(1)?, digits),
+        "min" => {
+            let y = arguments.get(1)?;
+            Ok(if y.compare(x) == Ordering::Less { y } else { x }.clone())
+        }
+        "max" => {
+            let y = arguments.get(1)?;
+            Ok(if y.compare(x) == Ordering::Greater {
+                y
+            } else {
+                x
+            }
+            .clone())
+        }
+        "clamp" => {
+            let (low, high) = (arguments.get(1)?, arguments.get(2)?);
+            let value = if x.compare(low) == Ordering::Less {
+                low
+            } else {
+                x
+            };
+            Ok(if value.compare(high) == Ordering::Greater {
+                high
+            } else {
+                value
+            }
+            .clone())
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Source of high-precision variable values used during evaluation.
+pub trait PrecisionContext: EvaluationContext {
+    /// Return the value of a variable with at least `digits` significant digits,
+    /// returning None when the variable is unknown.
+    fn precise_variable(&self, name: &str, digits: u64)
+        -> Option<Result<Decimal, ExpressionError>>;
+}
+
+/// Convert a float value to a Decimal, failing for infinite and NaN values.
+///
+/// # Arguments
+///
+/// * `value` - the float that is converted
+/// * `name` - name of the variable or literal the value belongs to, used in the error message
+///
+/// # Returns
+///
+/// `Result<Decimal, ExpressionError>` - exact decimal value or evaluation error
+///
+pub fn decimal_from_f64(value: f64, name: &str) -> Result<Decimal, ExpressionError> {
+    Decimal::from_f64(value).ok_or_else(|| {
+        precision_error(format!(
+            "Value {} of {} can not be represented as decimal",
+            value, name
+        ))
+    })
+}
+
+/// Return the exact value of a numeric literal.
+///
+/// The literal is read from the source so that numbers like `0.1` are not rounded to float.
+/// When the source does not match the parsed number the float value is used.
+fn literal(value: f64, source: Option<&str>) -> Result<Decimal, ExpressionError> {
+    source
+        .map(|text| text.trim_matches(|c: char| c == '(' || c == ')' || c.is_whitespace()))
+        .and_then(Decimal::parse)
+        .filter(|parsed| parsed.to_f64() == value)
+        .map(Ok)
+        .unwrap_or_else(|| decimal_from_f64(value, "number"))
+}
+
+/// Evaluate a syntax tree with decimal floating point numbers.
+///
+/// Intermediate results carry additional guard digits and the result is rounded to
+/// `digits` significant digits. Numeric literals are read exactly from `source`.
+/// Registered Python functions can not be used, since they only work with floats.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is evaluated
+/// * `source` - string the syntax tree was parsed from
+/// * `context` - context providing variable values
+/// * `digits` - number of significant digits of the result
+///
+/// # Returns
+///
+/// `Result<Decimal, ExpressionError>` - value of the expression or evaluation error
+///
+pub fn evaluate(
+    expression: &Expression,
+    source: &str,
+    context: &dyn PrecisionContext,
+    digits: usize,
+) -> Result<Decimal, ExpressionError> {
+    let digits = digits as u64;
+    Ok(evaluate_node(expression, source, context, digits + GUARD_DIGITS)?.round(digits))
+}
+
+/// Fail for nonzero values outside of the range 10^-MAX_ORDER to 10^MAX_ORDER.
+fn check_range(value: Decimal) -> Result<Decimal, ExpressionError> {
+    if value.is_zero() || value.order().abs() <= MAX_ORDER {
+        Ok(value)
+    } else {
+        Err(precision_error(format!(
+            "Value {} is outside of the supported range 1e-{} to 1e{}",
+            value.to_decimal_string(17),
+            MAX_ORDER,
+            MAX_ORDER
+        )))
+    }
+}
+
+/// Evaluate a node of a syntax tree with `digits` significant digits.
+fn evaluate_node(
+    expression: &Expression,
+    source: &str,
+    context: &dyn PrecisionContext,
+    digits: u64,
+) -> Result<Decimal, ExpressionError> {
+    evaluate_value(expression, source, context, digits).and_then(check_range)
+}
+
+/// Evaluate a node of a syntax tree without checking the range of its value.
+fn evaluate_value(
+    expression: &Expression,
+    source: &str,
+    context: &dyn PrecisionContext,
+    digits: u64,
+) -> Result<Decimal, ExpressionError> {
+    let evaluate = |node: &Expression| evaluate_node(node, source, context, digits);
+    match &expression.kind {
+        ExpressionKind::Number(value) => {
+            literal(*value, source.get(expression.start..expression.end))
+        }
+        ExpressionKind::Variable(name) => match context.precise_variable(name, digits) {
+            Some(value) => value,
+            None => match name.as_str() {
+                "pi" => Ok(pi(digits)),
+                "e" => exp(&Decimal::one(), digits),
+                _ => match context.resolve_variable(name) {
+                    Some(value) => value.and_then(|value| decimal_from_f64(value, name)),
+                    None => Err(ExpressionError::VariableNotSet { name: name.clone() }),
+                },
+            },
+        },
+        ExpressionKind::Unary { op, operand } => {
+            let value = evaluate(operand)?;
+            Ok(match op {
+                UnaryOperator::Plus => value,
+                UnaryOperator::Minus => value.neg(),
+                UnaryOperator::Not => Decimal::from_bool(value.is_zero()),
+            })
+        }
+        ExpressionKind::Binary { op, lhs, rhs } => {
+            let lhs = evaluate(lhs)?;
+            match op {
+                BinaryOperator::And if lhs.is_zero() => return Ok(Decimal::zero()),
+                BinaryOperator::Or if !lhs.is_zero() => return Ok(Decimal::one()),
+                _ => (),
+            }
+            let rhs = evaluate(rhs)?;
+            let ordering = lhs.compare(&rhs);
+            Ok(match op {
+                BinaryOperator::Add => lhs.add(&rhs, digits),
+                BinaryOperator::Subtract => lhs.sub(&rhs, digits),
+                BinaryOperator::Multiply => lhs.mul(&rhs, digits),
+                BinaryOperator::Divide => lhs.div(&rhs, digits)?,
+                BinaryOperator::Power => pow(&lhs, &rhs, digits)?,
+                BinaryOperator::Less => Decimal::from_bool(ordering == Ordering::Less),
+                BinaryOperator::LessEqual => Decimal::from_bool(ordering != Ordering::Greater),
+                BinaryOperator::Greater => Decimal::from_bool(ordering == Ordering::Greater),
+                BinaryOperator::GreaterEqual => Decimal::from_bool(ordering != Ordering::Less),
+                BinaryOperator::Equal => Decimal::from_bool(ordering == Ordering::Equal),
+                BinaryOperator::NotEqual => Decimal::from_bool(ordering != Ordering::Equal),
+                BinaryOperator::And | BinaryOperator::Or => Decimal::from_bool(!rhs.is_zero()),
+            })
+        }
+        ExpressionKind::Function { name, arguments } => {
+            if context.function_arity(name).is_some() {
+                return Err(precision_error(format!(
+                    "Registered function {} can not be evaluated in high precision",
+                    name
+                )));
+            }
+            let values = arguments
+                .iter()
+                .map(evaluate)
+                .collect::<Result<Vec<Decimal>, ExpressionError>>()?;
+            call_builtin(name, &values, digits).unwrap_or_else(|| {
+                Err(ExpressionError::FunctionNotFound {
+                    name: name.to_string(),
+                })
+            })
+        }
+        ExpressionKind::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => {
+            if evaluate(condition)?.is_zero() {
+                evaluate(otherwise)
+            } else {
+                evaluate(then)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expression;
+    use num_complex::Complex;
+
+    /// Context without variables and functions.
+    struct NoVariables;
+
+    impl EvaluationContext for NoVariables {
+        fn variable(&self, _name: &str) -> Option<Result<f64, ExpressionError>> {
+            None
+        }
+
+        fn function_arity(&self, _name: &str) -> Option<usize> {
+            None
+        }
+
+        fn call_function(
+            &self,
+            _name: &str,
+            _arguments: &[f64],
+        ) -> Option<Result<f64, ExpressionError>> {
+            None
+        }
+
+        fn call_function_complex(
+            &self,
+            _name: &str,
+            _arguments: &[Complex<f64>],
+        ) -> Option<Result<Complex<f64>, ExpressionError>> {
+            None
+        }
+    }
+
+    impl PrecisionContext for NoVariables {
+        fn precise_variable(
+            &self,
+            _name: &str,
+            _digits: u64,
+        ) -> Option<Result<Decimal, ExpressionError>> {
+            None
+        }
+    }
+
+    /// Evaluate an expression and format the result with `digits` significant digits.
+    fn evaluate_str(input: &str, digits: usize) -> Result<String, ExpressionError> {
+        let parsed = expression::parse(input)?;
+        evaluate(&parsed, input, &NoVariables, digits).map(|value| value.to_decimal_string(digits))
+    }
+
+    fn decimal(literal: &str) -> Decimal {
+        Decimal::parse(literal).unwrap()
+    }
+
+    #[test]
+    fn test_constants() {
+        assert_eq!(
+            pi(40).to_decimal_string(40),
+            "3.141592653589793238462643383279502884197"
+        );
+        assert_eq!(
+            exp(&Decimal::one(), 40).unwrap().to_decimal_string(40),
+            "2.718281828459045235360287471352662497757"
+        );
+        assert_eq!(
+            evaluate_str("pi", 30).unwrap(),
+            "3.14159265358979323846264338328"
+        );
+    }
+
+    #[test]
+    fn test_roots() {
+        assert_eq!(
+            root(&decimal("2"), 2, 40).to_decimal_string(40),
+            "1.41421356237309504880168872420969807857"
+        );
+        assert_eq!(
+            root(&decimal("2"), 3, 40).to_decimal_string(40),
+            "1.25992104989487316476721060727822835057"
+        );
+        assert_eq!(
+            evaluate_str("2^0.5", 40).unwrap(),
+            "1.41421356237309504880168872420969807857"
+        );
+        assert!(sqrt(&decimal("2").neg(), 40).is_err());
+    }
+
+    #[test]
+    fn test_logarithms() {
+        assert_eq!(
+            ln(&decimal("2"), 40).unwrap().to_decimal_string(40),
+            "0.6931471805599453094172321214581765680755"
+        );
+        assert_eq!(
+            ln(&decimal("10"), 40).unwrap().to_decimal_string(40),
+            "2.302585092994045684017991454684364207601"
+        );
+        assert_eq!(
+            exp(&decimal("1").neg(), 40).unwrap().to_decimal_string(40),
+            "0.3678794411714423215955237701614608674458"
+        );
+        assert!(ln(&Decimal::zero(), 40).is_err());
+        assert!(exp(&decimal("1e16"), 40).is_err());
+    }
+
+    #[test]
+    fn test_trigonometric() {
+        let (sin, cos) = sin_cos(&decimal("1"), 40);
+        assert_eq!(
+            sin.to_decimal_string(40),
+            "0.8414709848078965066525023216302989996226"
+        );
+        assert_eq!(
+            cos.to_decimal_string(40),
+            "0.5403023058681397174009366074429766037323"
+        );
+        assert_eq!(
+            asin(&decimal("0.5"), 40).unwrap().to_decimal_string(40),
+            "0.5235987755982988730771072305465838140329"
+        );
+        assert_eq!(
+            atan(&decimal("1"), 40).to_decimal_string(40),
+            "0.7853981633974483096156608458198757210493"
+        );
+        assert!(asin(&decimal("2"), 40).is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(evaluate_str("1 / 3", 10).unwrap(), "0.3333333333");
+        assert_eq!(evaluate_str("0.1 + 0.2", 40).unwrap(), "0.3");
+        assert_eq!(evaluate_str("(1 + 1e-30) - 1", 40).unwrap(), "1e-30");
+        assert_eq!(evaluate_str("2^-2", 40).unwrap(), "0.25");
+        assert_eq!(
+            evaluate_str("10^20 + 1", 25).unwrap(),
+            "100000000000000000001"
+        );
+        assert_eq!(decimal("12.5e-3").to_decimal_string(10), "0.0125");
+        assert_eq!(decimal("12.5e-3").to_f64(), 0.0125);
+        assert!(matches!(
+            evaluate_str("1 / 0", 40),
+            Err(ExpressionError::DivisionByZero)
+        ));
+    }
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!(evaluate_str("floor(-2.5)", 20).unwrap(), "-3");
+        assert_eq!(evaluate_str("ceil(2.1)", 20).unwrap(), "3");
+        assert_eq!(evaluate_str("round(2.5)", 20).unwrap(), "3");
+        assert_eq!(evaluate_str("round(-2.5)", 20).unwrap(), "-3");
+        assert_eq!(evaluate_str("trunc(-2.7)", 20).unwrap(), "-2");
+        assert_eq!(evaluate_str("parity(3.2)", 20).unwrap(), "-1");
+        assert_eq!(evaluate_str("floor(1e-9999)", 20).unwrap(), "0");
+        assert_eq!(evaluate_str("floor(-1e-9999)", 20).unwrap(), "-1");
+        assert_eq!(evaluate_str("round(1e-9999)", 20).unwrap(), "0");
+    }
+
+    #[test]
+    fn test_range() {
+        for input in &["floor(1e-1000000000)", "1e5000 * 1e6000", "sin(1e20000)"] {
+            assert!(matches!(
+                evaluate_str(input, 20),
+                Err(ExpressionError::PrecisionError { .. })
+            ));
+        }
+        assert!(evaluate_str("1e9999 * 10", 20).is_ok());
+    }
+}
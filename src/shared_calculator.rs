@@ -0,0 +1,351 @@
+// Copyright © 2020-2021 HQS Quantum Simulations GmbH. All Rights Reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+// in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the
+// License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+// express or implied. See the License for the specific language governing permissions and
+// limitations underthe License.
+
+//! shared_calculator module
+//!
+//! Calculator with a variable table that can be shared between Python threads.
+//! Evaluation releases the GIL, so several threads can evaluate expressions in parallel.
+
+use crate::calculator::{expression_error_to_py, extract_variables, validate_variable_name};
+use crate::convert_into_calculator_float;
+use crate::expression::{self, EvaluationContext, ExpressionError, Statement, StatementKind};
+use num_complex::Complex;
+use pyo3::exceptions::{PyKeyError, PyTypeError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3::{PyMappingProtocol, PySequenceProtocol};
+use qoqo_calculator::CalculatorFloat;
+use std::collections::HashMap;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Calculator whose variables can be read concurrently and are written exclusively.
+///
+/// All methods take the lock on the variables with the GIL released,
+/// so parsing and evaluation in one thread does not block other Python threads.
+#[pyclass(name = "SharedCalculator", module = "qoqo_calculator_pyo3")]
+#[derive(Clone, Debug)]
+pub struct SharedCalculatorWrapper {
+    variables: Arc<RwLock<HashMap<String, f64>>>,
+}
+
+#[pymethods]
+impl SharedCalculatorWrapper {
+    /// Create new Python instance of SharedCalculatorWrapper.
+    ///
+    /// # Arguments
+    ///
+    /// * `variables` - Optional mapping or iterable of (name, value) pairs of initial variables
+    /// * `kwargs` - Initial variables given as keyword arguments
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<Self>` - SharedCalculatorWrapper instance or corresponding Python error
+    ///
+    #[new]
+    #[args(variables = "None", kwargs = "**")]
+    fn new(variables: Option<&PyAny>, kwargs: Option<&PyDict>) -> PyResult<Self> {
+        let variables = extract_variables(variables, kwargs)?.into_iter().collect();
+        Ok(SharedCalculatorWrapper {
+            variables: Arc::new(RwLock::new(variables)),
+        })
+    }
+
+    /// Create Python copy of SharedCalculatorWrapper with its own variable table.
+    ///
+    /// # Returns
+    ///
+    /// `SharedCalculatorWrapper` - independent copy of the SharedCalculator
+    ///
+    fn __copy__(&self) -> SharedCalculatorWrapper {
+        SharedCalculatorWrapper {
+            variables: Arc::new(RwLock::new(self.variables())),
+        }
+    }
+
+    /// Create Python deep copy of SharedCalculatorWrapper with its own variable table.
+    ///
+    /// # Returns
+    ///
+    /// `SharedCalculatorWrapper` - independent copy of the SharedCalculator
+    ///
+    fn __deepcopy__(&self, _memodict: Py<PyAny>) -> SharedCalculatorWrapper {
+        self.__copy__()
+    }
+
+    /// Get new arguments for Python of SharedCalculatorWrapper.
+    ///
+    /// # Returns
+    ///
+    /// `((HashMap<String, f64>,), HashMap<String, String>)` - variables of the SharedCalculator
+    ///
+    fn __getnewargs_ex__(&self) -> ((HashMap<String, f64>,), HashMap<String, String>) {
+        ((self.variables(),), HashMap::new())
+    }
+
+    /// Set variable for SharedCalculator.
+    ///
+    /// # Arguments
+    ///
+    /// * `variable_string` - string of the variable name
+    /// * `val` - Float value of the variable
+    ///
+    fn set(&self, variable_string: &str, val: f64) -> PyResult<()> {
+        validate_variable_name(variable_string)?;
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        py.allow_threads(|| {
+            self.write_variables()
+                .insert(variable_string.to_string(), val)
+        });
+        Ok(())
+    }
+
+    /// Set several variables of the SharedCalculator at once.
+    ///
+    /// The variables are set while holding the write lock,
+    /// so other threads see either none or all of the new values.
+    ///
+    /// # Arguments
+    ///
+    /// * `variables` - Optional mapping or iterable of (name, value) pairs
+    /// * `kwargs` - Variables given as keyword arguments
+    ///
+    #[args(variables = "None", kwargs = "**")]
+    fn update(&self, variables: Option<&PyAny>, kwargs: Option<&PyDict>) -> PyResult<()> {
+        let variables = extract_variables(variables, kwargs)?;
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        py.allow_threads(|| self.write_variables().extend(variables));
+        Ok(())
+    }
+
+    /// Get the value of a variable of the SharedCalculator.
+    ///
+    /// # Arguments
+    ///
+    /// * `variable_string` - string of the variable name
+    /// * `default` - Value returned when the variable is not set
+    ///
+    /// # Returns
+    ///
+    /// `Option<f64>` - value of the variable or the default
+    ///
+    #[args(default = "None")]
+    fn get(&self, variable_string: &str, default: Option<f64>) -> Option<f64> {
+        self.variable(variable_string).or(default)
+    }
+
+    /// Remove a variable from the SharedCalculator.
+    ///
+    /// Item assignment and deletion are not supported, as they would require
+    /// exclusive access to the Python object while other threads are evaluating.
+    ///
+    /// # Arguments
+    ///
+    /// * `variable_string` - string of the variable name
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<f64>` - value of the removed variable, KeyError when it is not set
+    ///
+    fn remove(&self, variable_string: &str) -> PyResult<f64> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        py.allow_threads(|| self.write_variables().remove(variable_string))
+            .ok_or_else(|| PyKeyError::new_err(variable_string.to_string()))
+    }
+
+    /// Return a snapshot of all variables of the SharedCalculator.
+    ///
+    /// # Returns
+    ///
+    /// `HashMap<String, f64>` - names and values of the variables
+    ///
+    fn variables(&self) -> HashMap<String, f64> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        py.allow_threads(|| self.read_variables().clone())
+    }
+
+    /// Parse a string expression with the GIL released.
+    ///
+    /// The input can contain several statements separated by `;` or newlines.
+    /// Scripts without assignments only take the read lock and run concurrently,
+    /// scripts with assignments take the write lock for the whole script.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Expression that is parsed
+    ///
+    /// # Returns
+    ///
+    /// `PyResult<f64>` - the value of the last statement
+    ///
+    fn parse_str(&self, input: &str) -> PyResult<f64> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        py.allow_threads(|| self.evaluate_str(input))
+            .map_err(|err| expression_error_to_py(py, err, input))
+    }
+
+    /// Parse an input to float with the GIL released.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Parsed string CalculatorFloat or returns float value
+    ///
+    fn parse_get(&self, input: &PyAny) -> PyResult<f64> {
+        let converted = convert_into_calculator_float(input)
+            .map_err(|_| PyTypeError::new_err("Input can not be converted to Calculator Float"))?;
+        match converted {
+            CalculatorFloat::Float(x) => Ok(x),
+            CalculatorFloat::Str(x) => self.parse_str(&x),
+        }
+    }
+}
+
+impl SharedCalculatorWrapper {
+    /// Take the read lock on the variables.
+    ///
+    /// A poisoned lock is recovered, as the variable table is never left inconsistent.
+    fn read_variables(&self) -> RwLockReadGuard<'_, HashMap<String, f64>> {
+        self.variables
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Take the write lock on the variables.
+    fn write_variables(&self) -> RwLockWriteGuard<'_, HashMap<String, f64>> {
+        self.variables
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Return the value of a variable, taking the read lock with the GIL released.
+    fn variable(&self, name: &str) -> Option<f64> {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        py.allow_threads(|| self.read_variables().get(name).copied())
+    }
+
+    /// Parse and evaluate a script without access to Python.
+    fn evaluate_str(&self, input: &str) -> Result<f64, ExpressionError> {
+        let statements = expression::parse_script(input)?;
+        let assigns = statements
+            .iter()
+            .any(|statement| matches!(statement.kind, StatementKind::Assignment { .. }));
+        if assigns {
+            let mut variables = self.write_variables();
+            evaluate_statements(input, &statements, |statement| {
+                let value = evaluate_statement(statement, &variables)?;
+                if let StatementKind::Assignment { name, .. } = &statement.kind {
+                    variables.insert(name.clone(), value);
+                }
+                Ok(value)
+            })
+        } else {
+            let variables = self.read_variables();
+            evaluate_statements(input, &statements, |statement| {
+                evaluate_statement(statement, &variables)
+            })
+        }
+    }
+}
+
+/// Evaluate the statements of a script in order, returning the value of the last one.
+fn evaluate_statements(
+    input: &str,
+    statements: &[Statement],
+    mut evaluate: impl FnMut(&Statement) -> Result<f64, ExpressionError>,
+) -> Result<f64, ExpressionError> {
+    let multiple_statements = statements.len() > 1;
+    let mut value = None;
+    for (index, statement) in statements.iter().enumerate() {
+        let result = evaluate(statement).map_err(|err| {
+            if multiple_statements {
+                ExpressionError::StatementFailed {
+                    index: index + 1,
+                    statement: input[statement.start..statement.end].to_string(),
+                    err: Box::new(err),
+                }
+            } else {
+                err
+            }
+        })?;
+        value = Some(result);
+    }
+    value.ok_or(ExpressionError::EmptyScript)
+}
+
+/// Evaluate the expression of a single statement with the given variables.
+fn evaluate_statement(
+    statement: &Statement,
+    variables: &HashMap<String, f64>,
+) -> Result<f64, ExpressionError> {
+    let context = VariableTable { variables };
+    expression::check_functions(statement.expression(), &context)?;
+    expression::evaluate(statement.expression(), &context)
+}
+
+/// Evaluation context providing only the variables of a SharedCalculator.
+struct VariableTable<'a> {
+    variables: &'a HashMap<String, f64>,
+}
+
+impl EvaluationContext for VariableTable<'_> {
+    fn variable(&self, name: &str) -> Option<Result<f64, ExpressionError>> {
+        self.variables.get(name).copied().map(Ok)
+    }
+
+    fn function_arity(&self, _name: &str) -> Option<usize> {
+        None
+    }
+
+    fn call_function(
+        &self,
+        _name: &str,
+        _arguments: &[f64],
+    ) -> Option<Result<f64, ExpressionError>> {
+        None
+    }
+
+    fn call_function_complex(
+        &self,
+        _name: &str,
+        _arguments: &[Complex<f64>],
+    ) -> Option<Result<Complex<f64>, ExpressionError>> {
+        None
+    }
+}
+
+#[pyproto]
+impl PyMappingProtocol for SharedCalculatorWrapper {
+    /// Return the number of variables set in the SharedCalculator.
+    fn __len__(&self) -> usize {
+        let gil = pyo3::Python::acquire_gil();
+        let py = gil.python();
+        py.allow_threads(|| self.read_variables().len())
+    }
+
+    /// Return the value of a variable, raising KeyError when it is not set.
+    fn __getitem__(&self, key: String) -> PyResult<f64> {
+        self.variable(&key).ok_or_else(|| PyKeyError::new_err(key))
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for SharedCalculatorWrapper {
+    /// Return True when the variable is set in the SharedCalculator.
+    fn __contains__(&self, key: String) -> bool {
+        self.variable(&key).is_some()
+    }
+}
@@ -0,0 +1,570 @@
+// Copyright © 2020-2021 HQS Quantum Simulations GmbH. All Rights Reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+// in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the
+// License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+// express or implied. See the License for the specific language governing permissions and
+// limitations underthe License.
+
+//! uncertainty module
+//!
+//! Evaluates expressions together with their gradient with respect to a set of variables
+//! and propagates the uncertainties of the variables to first order. Expressions can also be
+//! evaluated for many random draws of their variables at once.
+
+use crate::expression::{
+    self, BinaryOperator, EvaluationContext, Expression, ExpressionError, ExpressionKind,
+    UnaryOperator,
+};
+use std::collections::HashMap;
+use std::f64::consts::{LN_10, LN_2, PI};
+
+/// Source of derived variables used when differentiating an expression.
+pub trait GradientContext: EvaluationContext {
+    /// Return the expression of a derived variable, returning None when the variable
+    /// is not derived or shadowed by a plain variable.
+    fn derived_expression(&self, name: &str) -> Option<&Expression>;
+}
+
+/// Value of a sub-expression together with its gradient.
+#[derive(Debug, Clone)]
+struct Dual {
+    value: f64,
+    gradient: Vec<f64>,
+}
+
+impl Dual {
+    /// Create a value that does not depend on any of the variables.
+    fn constant(value: f64, size: usize) -> Dual {
+        Dual {
+            value,
+            gradient: vec![0.0; size],
+        }
+    }
+
+    /// Return true when the value does not depend on any of the variables.
+    fn is_constant(&self) -> bool {
+        self.gradient.iter().all(|partial| *partial == 0.0)
+    }
+}
+
+/// Combine the gradients of operands weighted with the partial derivatives of an operation.
+///
+/// Constant operands are skipped, so infinite or undefined partial derivatives
+/// with respect to them do not spoil the gradient.
+fn chain(value: f64, operands: &[Dual], partials: &[f64]) -> Dual {
+    let size = operands.first().map_or(0, |operand| operand.gradient.len());
+    let mut result = Dual::constant(value, size);
+    for (operand, partial) in operands.iter().zip(partials) {
+        if operand.is_constant() {
+            continue;
+        }
+        for (total, derivative) in result.gradient.iter_mut().zip(operand.gradient.iter()) {
+            *total += partial * derivative;
+        }
+    }
+    result
+}
+
+/// Return the partial derivatives of a built-in function with respect to its arguments.
+fn builtin_partials(name: &str, arguments: &[f64], value: f64) -> Option<Vec<f64>> {
+    let x = *arguments.first()?;
+    let derivative = match name {
+        "sin" => x.cos(),
+        "cos" => -x.sin(),
+        "tan" => 1.0 + value * value,
+        "asin" => 1.0 / (1.0 - x * x).sqrt(),
+        "acos" => -1.0 / (1.0 - x * x).sqrt(),
+        "atan" => 1.0 / (1.0 + x * x),
+        "sinh" => x.cosh(),
+        "cosh" => x.sinh(),
+        "tanh" => 1.0 - value * value,
+        "asinh" | "arsinh" => 1.0 / (x * x + 1.0).sqrt(),
+        "acosh" | "arcosh" => 1.0 / (x * x - 1.0).sqrt(),
+        "atanh" | "artanh" => 1.0 / (1.0 - x * x),
+        "exp" => value,
+        "exp2" => value * LN_2,
+        "expm1" => value + 1.0,
+        "log" | "ln" => 1.0 / x,
+        "log10" => 1.0 / (x * LN_10),
+        "log2" => 1.0 / (x * LN_2),
+        "log1p" => 1.0 / (1.0 + x),
+        "sqrt" => 0.5 / value,
+        "cbrt" => 1.0 / (3.0 * value * value),
+        "abs" => {
+            if x == 0.0 {
+                0.0
+            } else {
+                x.signum()
+            }
+        }
+        "erf" => 2.0 / PI.sqrt() * (-x * x).exp(),
+        "sign" | "signum" | "floor" | "ceil" | "round" | "trunc" | "imag" | "arg" | "delta"
+        | "theta" | "parity" => 0.0,
+        "fract" | "conj" | "real" => 1.0,
+        "recip" => -value * value,
+        "atan2" => {
+            let other = *arguments.get(1)?;
+            let radius = x * x + other * other;
+            return Some(vec![other / radius, -x / radius]);
+        }
+        "hypot" => return Some(vec![x / value, *arguments.get(1)? / value]),
+        "pow" => {
+            let exponent = *arguments.get(1)?;
+            return Some(vec![exponent * x.powf(exponent - 1.0), value * x.ln()]);
+        }
+        "min" => {
+            return Some(if x <= *arguments.get(1)? {
+                vec![1.0, 0.0]
+            } else {
+                vec![0.0, 1.0]
+            })
+        }
+        "max" => {
+            return Some(if x >= *arguments.get(1)? {
+                vec![1.0, 0.0]
+            } else {
+                vec![0.0, 1.0]
+            })
+        }
+        "clamp" => {
+            let (low, high) = (*arguments.get(1)?, *arguments.get(2)?);
+            return Some(if x > high {
+                vec![0.0, 0.0, 1.0]
+            } else if x < low {
+                vec![0.0, 1.0, 0.0]
+            } else {
+                vec![1.0, 0.0, 0.0]
+            });
+        }
+        _ => return None,
+    };
+    Some(vec![derivative])
+}
+
+/// Return the partial derivative of a user defined function by central differences.
+fn numerical_partial<C: GradientContext>(
+    name: &str,
+    arguments: &[f64],
+    index: usize,
+    context: &C,
+) -> Result<f64, ExpressionError> {
+    let step = f64::EPSILON.cbrt() * arguments[index].abs().max(1.0);
+    let mut shifted = arguments.to_vec();
+    shifted[index] = arguments[index] + step;
+    let upper = expression::apply_function(name, &shifted, context)?;
+    shifted[index] = arguments[index] - step;
+    let lower = expression::apply_function(name, &shifted, context)?;
+    Ok((upper - lower) / (2.0 * step))
+}
+
+/// Evaluate a syntax tree to a float together with its gradient.
+///
+/// Derivatives of built-in functions and operators are computed exactly, derivatives of
+/// registered Python functions and of the gamma functions by central differences.
+/// Comparisons, logical operators and rounding functions have zero derivative.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is evaluated
+/// * `context` - context providing variable values and user defined functions
+/// * `variables` - names of the variables the gradient is computed for
+///
+/// # Returns
+///
+/// `Result<(f64, Vec<f64>), ExpressionError>` - value of the expression and its partial
+///                                              derivatives in the order of `variables`
+///
+pub fn evaluate_gradient<C: GradientContext>(
+    expression: &Expression,
+    context: &C,
+    variables: &[String],
+) -> Result<(f64, Vec<f64>), ExpressionError> {
+    let result = evaluate_node(expression, context, variables)?;
+    Ok((result.value, result.gradient))
+}
+
+/// Evaluate a node of a syntax tree together with its gradient.
+fn evaluate_node<C: GradientContext>(
+    expression: &Expression,
+    context: &C,
+    variables: &[String],
+) -> Result<Dual, ExpressionError> {
+    let size = variables.len();
+    match &expression.kind {
+        ExpressionKind::Number(value) => Ok(Dual::constant(*value, size)),
+        ExpressionKind::Variable(name) => {
+            let index = variables.iter().position(|variable| variable == name);
+            if index.is_none() {
+                if let Some(derived) = context.derived_expression(name) {
+                    return evaluate_node(derived, context, variables).map_err(|err| {
+                        ExpressionError::DefinitionFailed {
+                            name: name.clone(),
+                            err: Box::new(err),
+                        }
+                    });
+                }
+            }
+            let value = expression::lookup_variable(name, context)
+                .unwrap_or_else(|| Err(ExpressionError::VariableNotSet { name: name.clone() }))?;
+            let mut result = Dual::constant(value, size);
+            if let Some(index) = index {
+                result.gradient[index] = 1.0;
+            }
+            Ok(result)
+        }
+        ExpressionKind::Unary { op, operand } => {
+            let operand = evaluate_node(operand, context, variables)?;
+            Ok(match op {
+                UnaryOperator::Plus => operand,
+                UnaryOperator::Minus => chain(-operand.value, &[operand], &[-1.0]),
+                UnaryOperator::Not => Dual::constant(
+                    expression::apply_unary(UnaryOperator::Not, operand.value),
+                    size,
+                ),
+            })
+        }
+        ExpressionKind::Binary { op, lhs, rhs } => {
+            let lhs = evaluate_node(lhs, context, variables)?;
+            if let Some(value) = expression::short_circuit(*op, lhs.value) {
+                return Ok(Dual::constant(value, size));
+            }
+            let rhs = evaluate_node(rhs, context, variables)?;
+            let (x, y) = (lhs.value, rhs.value);
+            let value = expression::apply_binary(*op, x, y)?;
+            let partials = match op {
+                BinaryOperator::Add => vec![1.0, 1.0],
+                BinaryOperator::Subtract => vec![1.0, -1.0],
+                BinaryOperator::Multiply => vec![y, x],
+                BinaryOperator::Divide => vec![1.0 / y, -x / (y * y)],
+                BinaryOperator::Power => {
+                    builtin_partials("pow", &[x, y], value).unwrap_or_default()
+                }
+                _ => return Ok(Dual::constant(value, size)),
+            };
+            Ok(chain(value, &[lhs, rhs], &partials))
+        }
+        ExpressionKind::Function { name, arguments } => {
+            let operands = arguments
+                .iter()
+                .map(|argument| evaluate_node(argument, context, variables))
+                .collect::<Result<Vec<Dual>, ExpressionError>>()?;
+            let values: Vec<f64> = operands.iter().map(|operand| operand.value).collect();
+            let value = expression::apply_function(name, &values, context)?;
+            let partials = if context.function_arity(name).is_some() {
+                operands
+                    .iter()
+                    .enumerate()
+                    .map(|(index, operand)| {
+                        if operand.is_constant() {
+                            Ok(0.0)
+                        } else {
+                            numerical_partial(name, &values, index, context)
+                        }
+                    })
+                    .collect::<Result<Vec<f64>, ExpressionError>>()?
+            } else if let Some(partials) = builtin_partials(name, &values, value) {
+                partials
+            } else {
+                numerical_partial(name, &values, 0, context).map(|partial| vec![partial])?
+            };
+            Ok(chain(value, &operands, &partials))
+        }
+        ExpressionKind::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => {
+            if evaluate_node(condition, context, variables)?.value != 0.0 {
+                evaluate_node(then, context, variables)
+            } else {
+                evaluate_node(otherwise, context, variables)
+            }
+        }
+    }
+}
+
+/// Evaluate a syntax tree for arrays of draws of some of its variables.
+///
+/// Every node is evaluated once for all draws. The right operand of `&&` and `||` and
+/// the branches of a conditional are only evaluated for the draws that need them,
+/// so the results and errors are the same as when evaluating draw by draw.
+///
+/// # Arguments
+///
+/// * `expression` - syntax tree that is evaluated
+/// * `context` - context providing the other variable values and user defined functions
+/// * `draws` - arrays of values of the drawn variables, all of length `n`
+/// * `n` - number of draws
+///
+/// # Returns
+///
+/// `Result<Vec<f64>, ExpressionError>` - value of the expression for every draw
+///
+pub fn evaluate_draws<C: GradientContext>(
+    expression: &Expression,
+    context: &C,
+    draws: &HashMap<String, Vec<f64>>,
+    n: usize,
+) -> Result<Vec<f64>, ExpressionError> {
+    let indices: Vec<usize> = (0..n).collect();
+    evaluate_draws_node(expression, context, draws, &indices)
+}
+
+/// Evaluate a node of a syntax tree for the draws with the given indices.
+fn evaluate_draws_node<C: GradientContext>(
+    expression: &Expression,
+    context: &C,
+    draws: &HashMap<String, Vec<f64>>,
+    indices: &[usize],
+) -> Result<Vec<f64>, ExpressionError> {
+    if indices.is_empty() {
+        return Ok(Vec::new());
+    }
+    match &expression.kind {
+        ExpressionKind::Number(value) => Ok(vec![*value; indices.len()]),
+        ExpressionKind::Variable(name) => {
+            if let Some(values) = draws.get(name) {
+                return Ok(indices.iter().map(|index| values[*index]).collect());
+            }
+            if let Some(derived) = context.derived_expression(name) {
+                return evaluate_draws_node(derived, context, draws, indices).map_err(|err| {
+                    ExpressionError::DefinitionFailed {
+                        name: name.clone(),
+                        err: Box::new(err),
+                    }
+                });
+            }
+            let value = expression::lookup_variable(name, context)
+                .unwrap_or_else(|| Err(ExpressionError::VariableNotSet { name: name.clone() }))?;
+            Ok(vec![value; indices.len()])
+        }
+        ExpressionKind::Unary { op, operand } => {
+            Ok(evaluate_draws_node(operand, context, draws, indices)?
+                .into_iter()
+                .map(|value| expression::apply_unary(*op, value))
+                .collect())
+        }
+        ExpressionKind::Binary { op, lhs, rhs } => {
+            let mut values = evaluate_draws_node(lhs, context, draws, indices)?;
+            let mut pending = Vec::new();
+            for (position, value) in values.iter_mut().enumerate() {
+                match expression::short_circuit(*op, *value) {
+                    Some(short) => *value = short,
+                    None => pending.push(position),
+                }
+            }
+            let pending_indices: Vec<usize> =
+                pending.iter().map(|position| indices[*position]).collect();
+            let rhs = evaluate_draws_node(rhs, context, draws, &pending_indices)?;
+            for (position, rhs) in pending.into_iter().zip(rhs) {
+                values[position] = expression::apply_binary(*op, values[position], rhs)?;
+            }
+            Ok(values)
+        }
+        ExpressionKind::Function { name, arguments } => {
+            let operands = arguments
+                .iter()
+                .map(|argument| evaluate_draws_node(argument, context, draws, indices))
+                .collect::<Result<Vec<Vec<f64>>, ExpressionError>>()?;
+            (0..indices.len())
+                .map(|position| {
+                    let values: Vec<f64> =
+                        operands.iter().map(|operand| operand[position]).collect();
+                    expression::apply_function(name, &values, context)
+                })
+                .collect()
+        }
+        ExpressionKind::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => {
+            let condition = evaluate_draws_node(condition, context, draws, indices)?;
+            let (taken, skipped): (Vec<usize>, Vec<usize>) =
+                (0..indices.len()).partition(|position| condition[*position] != 0.0);
+            let mut values = vec![0.0; indices.len()];
+            for (positions, branch) in [(taken, then), (skipped, otherwise)].iter() {
+                let branch_indices: Vec<usize> = positions
+                    .iter()
+                    .map(|position| indices[*position])
+                    .collect();
+                let branch_values = evaluate_draws_node(branch, context, draws, &branch_indices)?;
+                for (position, value) in positions.iter().zip(branch_values) {
+                    values[*position] = value;
+                }
+            }
+            Ok(values)
+        }
+    }
+}
+
+/// Tolerance of the positive semi-definiteness check of correlation matrices.
+const CORRELATION_TOLERANCE: f64 = 1e-12;
+
+/// Factorize a correlation matrix into a lower triangular matrix L with L·Lᵀ equal to the matrix.
+///
+/// Columns belonging to a vanishing pivot are set to zero, so singular positive
+/// semi-definite matrices are accepted.
+///
+/// # Arguments
+///
+/// * `matrix` - symmetric matrix to be factorized
+///
+/// # Returns
+///
+/// `Option<Vec<Vec<f64>>>` - the factor L, None when the matrix is not positive semi-definite
+///
+pub fn cholesky(matrix: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let size = matrix.len();
+    let mut factor = vec![vec![0.0; size]; size];
+    for j in 0..size {
+        let pivot = matrix[j][j] - (0..j).map(|k| factor[j][k] * factor[j][k]).sum::<f64>();
+        if pivot < -CORRELATION_TOLERANCE {
+            return None;
+        }
+        let diagonal = if pivot > CORRELATION_TOLERANCE {
+            pivot.sqrt()
+        } else {
+            0.0
+        };
+        factor[j][j] = diagonal;
+        for i in j + 1..size {
+            let residual = matrix[i][j] - (0..j).map(|k| factor[i][k] * factor[j][k]).sum::<f64>();
+            if diagonal > 0.0 {
+                factor[i][j] = residual / diagonal;
+            } else if residual.abs() > CORRELATION_TOLERANCE.sqrt() {
+                return None;
+            }
+        }
+    }
+    Some(factor)
+}
+
+/// Propagate the uncertainties of variables to first order.
+///
+/// # Arguments
+///
+/// * `gradient` - partial derivatives of the expression with respect to the variables
+/// * `sigmas` - standard deviations of the variables
+/// * `factor` - Cholesky factor of the correlation matrix of the variables (see [cholesky]),
+///   uncorrelated when None
+///
+/// # Returns
+///
+/// `f64` - standard deviation of the expression
+///
+pub fn propagate(gradient: &[f64], sigmas: &[f64], factor: Option<&[Vec<f64>]>) -> f64 {
+    let weighted: Vec<f64> = gradient
+        .iter()
+        .zip(sigmas)
+        .map(|(partial, sigma)| if *sigma == 0.0 { 0.0 } else { partial * sigma })
+        .collect();
+    let variance: f64 = match factor {
+        // The variance wᵀ·C·w equals |Lᵀ·w|², which can not become negative.
+        Some(factor) => (0..weighted.len())
+            .map(|k| {
+                let projection: f64 = weighted
+                    .iter()
+                    .zip(factor)
+                    .map(|(term, row)| term * row[k])
+                    .sum();
+                projection * projection
+            })
+            .sum(),
+        None => weighted.iter().map(|term| term * term).sum(),
+    };
+    variance.sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_complex::Complex;
+
+    /// Context with fixed variables and a derived variable h = u^2 + x.
+    struct Variables {
+        values: HashMap<String, f64>,
+        derived: Expression,
+    }
+
+    impl EvaluationContext for Variables {
+        fn variable(&self, name: &str) -> Option<Result<f64, ExpressionError>> {
+            match name {
+                "h" => Some(expression::evaluate(&self.derived, self)),
+                _ => self.values.get(name).map(|value| Ok(*value)),
+            }
+        }
+
+        fn function_arity(&self, _name: &str) -> Option<usize> {
+            None
+        }
+
+        fn call_function(
+            &self,
+            _name: &str,
+            _arguments: &[f64],
+        ) -> Option<Result<f64, ExpressionError>> {
+            None
+        }
+
+        fn call_function_complex(
+            &self,
+            _name: &str,
+            _arguments: &[Complex<f64>],
+        ) -> Option<Result<Complex<f64>, ExpressionError>> {
+            None
+        }
+    }
+
+    impl GradientContext for Variables {
+        fn derived_expression(&self, name: &str) -> Option<&Expression> {
+            match name {
+                "h" => Some(&self.derived),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_evaluate_draws_matches_evaluate() {
+        let draws: HashMap<String, Vec<f64>> = vec![("u".to_string(), vec![-1.5, 0.0, 0.5, 2.0])]
+            .into_iter()
+            .collect();
+        let mut context = Variables {
+            values: vec![("x".to_string(), 0.25)].into_iter().collect(),
+            derived: expression::parse("u^2 + x").unwrap(),
+        };
+        for input in [
+            "u * x - 1",
+            "-u^2",
+            "h * 2",
+            "max(u, x) + sin(u)",
+            "u != 0 ? 1 / u : x",
+            "u == 0 || 1 / u > 1",
+            "u != 0 && 1 / u < 0",
+            "pi * u",
+        ]
+        .iter()
+        {
+            let parsed = expression::parse(input).unwrap();
+            let values = evaluate_draws(&parsed, &context, &draws, 4).unwrap();
+            for (index, value) in values.iter().enumerate() {
+                context.values.insert("u".to_string(), draws["u"][index]);
+                let expected = expression::evaluate(&parsed, &context).unwrap();
+                assert_eq!(*value, expected, "{} for draw {}", input, index);
+            }
+            context.values.remove("u");
+        }
+        let parsed = expression::parse("1 / u").unwrap();
+        assert!(evaluate_draws(&parsed, &context, &draws, 4).is_err());
+        let parsed = expression::parse("y * u").unwrap();
+        assert!(evaluate_draws(&parsed, &context, &draws, 0)
+            .unwrap()
+            .is_empty());
+    }
+}
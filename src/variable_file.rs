@@ -0,0 +1,193 @@
+// Copyright © 2020-2021 HQS Quantum Simulations GmbH. All Rights Reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+// in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the
+// License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+// express or implied. See the License for the specific language governing permissions and
+// limitations underthe License.
+
+//! variable_file module
+//!
+//! Reading and writing tables of Calculator variables in JSON and TOML files.
+//!
+//! JSON has no literals for non-finite numbers, they are written as the strings
+//! `inf`, `-inf` and `nan` that TOML uses for them.
+
+use pyo3::exceptions::{PyOSError, PyValueError};
+use pyo3::prelude::*;
+use qoqo_calculator::CalculatorFloat;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// Supported formats of variable files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileFormat {
+    Json,
+    Toml,
+}
+
+/// Determine the format of a variable file from its extension.
+fn file_format(path: &Path) -> PyResult<FileFormat> {
+    match path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("json") => Ok(FileFormat::Json),
+        Some("toml") => Ok(FileFormat::Toml),
+        _ => Err(PyValueError::new_err(format!(
+            "Unsupported file format of {}; expected a .json or .toml file",
+            path.display()
+        ))),
+    }
+}
+
+/// Return the TOML spelling of a non-finite number.
+fn non_finite_to_str(x: f64) -> &'static str {
+    if x.is_nan() {
+        "nan"
+    } else if x > 0.0 {
+        "inf"
+    } else {
+        "-inf"
+    }
+}
+
+/// Return the non-finite number of its TOML spelling, None for all other strings.
+fn non_finite_from_str(value: &str) -> Option<f64> {
+    match value {
+        "inf" | "+inf" => Some(f64::INFINITY),
+        "-inf" => Some(f64::NEG_INFINITY),
+        "nan" | "+nan" | "-nan" => Some(f64::NAN),
+        _ => None,
+    }
+}
+
+/// Read the variable definitions of a JSON or TOML file.
+///
+/// The file contains a flat table mapping variable names to numbers or expression strings.
+///
+/// # Arguments
+///
+/// * `path` - path of the file
+///
+/// # Returns
+///
+/// `PyResult<Vec<(String, CalculatorFloat)>>` - names and values or expressions of the variables
+///
+pub fn read_definitions(path: &Path) -> PyResult<Vec<(String, CalculatorFloat)>> {
+    let format = file_format(path)?;
+    let content = fs::read_to_string(path)
+        .map_err(|err| PyOSError::new_err(format!("{}: {}", path.display(), err)))?;
+    let parse_error =
+        |err: &dyn std::fmt::Display| PyValueError::new_err(format!("{}: {}", path.display(), err));
+    let invalid_value = |name: &str| {
+        PyValueError::new_err(format!(
+            "{}: value of key {} is neither a number nor an expression string",
+            path.display(),
+            name
+        ))
+    };
+    let mut definitions = Vec::new();
+    match format {
+        FileFormat::Json => {
+            let table: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(&content).map_err(|err| parse_error(&err))?;
+            for (name, value) in table {
+                let value = match value {
+                    serde_json::Value::Number(number) => match number.as_f64() {
+                        Some(x) => CalculatorFloat::Float(x),
+                        None => return Err(invalid_value(&name)),
+                    },
+                    serde_json::Value::String(expression) => {
+                        match non_finite_from_str(&expression) {
+                            Some(x) => CalculatorFloat::Float(x),
+                            None => CalculatorFloat::Str(expression),
+                        }
+                    }
+                    _ => return Err(invalid_value(&name)),
+                };
+                definitions.push((name, value));
+            }
+        }
+        FileFormat::Toml => {
+            let table: toml::value::Table =
+                toml::from_str(&content).map_err(|err| parse_error(&err))?;
+            for (name, value) in table {
+                let value = match value {
+                    toml::Value::Integer(x) => CalculatorFloat::Float(x as f64),
+                    toml::Value::Float(x) => CalculatorFloat::Float(x),
+                    toml::Value::String(expression) => CalculatorFloat::Str(expression),
+                    _ => return Err(invalid_value(&name)),
+                };
+                definitions.push((name, value));
+            }
+        }
+    }
+    Ok(definitions)
+}
+
+/// Write variables and expressions of derived variables to a JSON or TOML file.
+///
+/// # Arguments
+///
+/// * `path` - path of the file
+/// * `variables` - names and values of plain variables
+/// * `definitions` - names and expressions of derived variables
+///
+pub fn write_definitions(
+    path: &Path,
+    variables: &HashMap<String, f64>,
+    definitions: &HashMap<String, String>,
+) -> PyResult<()> {
+    let format = file_format(path)?;
+    let mut table: BTreeMap<&str, CalculatorFloat> = variables
+        .iter()
+        .map(|(name, value)| (name.as_str(), CalculatorFloat::Float(*value)))
+        .collect();
+    table.extend(
+        definitions
+            .iter()
+            .map(|(name, source)| (name.as_str(), CalculatorFloat::Str(source.clone()))),
+    );
+    let content = match format {
+        FileFormat::Json => {
+            let mut map = serde_json::Map::new();
+            for (name, value) in table {
+                let value = match value {
+                    CalculatorFloat::Float(x) => serde_json::Number::from_f64(x)
+                        .map(serde_json::Value::Number)
+                        .unwrap_or_else(|| {
+                            serde_json::Value::String(non_finite_to_str(x).to_string())
+                        }),
+                    CalculatorFloat::Str(expression) => serde_json::Value::String(expression),
+                };
+                map.insert(name.to_string(), value);
+            }
+            serde_json::to_string_pretty(&map)
+                .map_err(|err| PyValueError::new_err(format!("{}: {}", path.display(), err)))?
+        }
+        FileFormat::Toml => {
+            let map: toml::value::Table = table
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        CalculatorFloat::Float(x) => toml::Value::Float(x),
+                        CalculatorFloat::Str(expression) => toml::Value::String(expression),
+                    };
+                    (name.to_string(), value)
+                })
+                .collect();
+            toml::to_string(&map)
+                .map_err(|err| PyValueError::new_err(format!("{}: {}", path.display(), err)))?
+        }
+    };
+    fs::write(path, content)
+        .map_err(|err| PyOSError::new_err(format!("{}: {}", path.display(), err)))
+}